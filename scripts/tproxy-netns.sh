@@ -0,0 +1,83 @@
+#!/bin/sh
+# Checks TPROXY mode on one Linux box, as root, with three network namespaces:
+#
+#   client 10.0.1.2 --- 10.0.1.1 gw 10.0.2.1 --- 10.0.2.2 srv
+#
+# The gateway runs both the local and the remote server. Its iptables TPROXY
+# rules divert the client's TCP and UDP traffic to the local server, which
+# tunnels it to the remote, which reaches srv. The client checks an HTTP
+# fetch and a UDP echo, whose reply must come from srv's address.
+#
+# usage: scripts/tproxy-netns.sh [path/to/proxy-rs]
+set -eu
+
+BIN=$(realpath "${1:-target/release/proxy-rs}")
+KEY=01234567890123456789012345678901
+NS="proxy-rs-client proxy-rs-gw proxy-rs-srv"
+
+cleanup() {
+    for ns in $NS; do
+        ip netns pids "$ns" 2>/dev/null | xargs -r kill 2>/dev/null || true
+        ip netns del "$ns" 2>/dev/null || true
+    done
+}
+trap cleanup EXIT
+cleanup
+
+for ns in $NS; do
+    ip netns add "$ns"
+    ip -n "$ns" link set lo up
+done
+ip link add veth-client netns proxy-rs-client type veth peer name veth-gc netns proxy-rs-gw
+ip link add veth-srv netns proxy-rs-srv type veth peer name veth-gs netns proxy-rs-gw
+
+ip -n proxy-rs-client addr add 10.0.1.2/24 dev veth-client
+ip -n proxy-rs-client link set veth-client up
+ip -n proxy-rs-client route add default via 10.0.1.1
+
+ip -n proxy-rs-srv addr add 10.0.2.2/24 dev veth-srv
+ip -n proxy-rs-srv link set veth-srv up
+ip -n proxy-rs-srv route add default via 10.0.2.1
+
+GW="ip netns exec proxy-rs-gw"
+ip -n proxy-rs-gw addr add 10.0.1.1/24 dev veth-gc
+ip -n proxy-rs-gw addr add 10.0.2.1/24 dev veth-gs
+ip -n proxy-rs-gw link set veth-gc up
+ip -n proxy-rs-gw link set veth-gs up
+$GW sysctl -qw net.ipv4.ip_forward=1
+# marked packets are delivered locally, to the transparent sockets
+ip -n proxy-rs-gw rule add fwmark 1 lookup 100
+ip -n proxy-rs-gw route add local 0.0.0.0/0 dev lo table 100
+for proto in tcp udp; do
+    $GW iptables -t mangle -A PREROUTING -i veth-gc -p $proto \
+        -j TPROXY --on-port 12345 --tproxy-mark 1
+done
+
+SRV="ip netns exec proxy-rs-srv"
+$SRV python3 -m http.server 8080 --bind 10.0.2.2 >/dev/null 2>&1 &
+$SRV python3 -c '
+import socket
+s = socket.socket(socket.AF_INET, socket.SOCK_DGRAM)
+s.bind(("10.0.2.2", 9000))
+while True:
+    data, addr = s.recvfrom(65535)
+    s.sendto(data, addr)
+' &
+
+$GW "$BIN" remote -l 127.0.0.1:8171 -k $KEY --allow-cidr 10.0.2.0/24 &
+$GW "$BIN" local -l 127.0.0.1:6355 -r 127.0.0.1:8171 -k $KEY \
+    --tproxy-listen 0.0.0.0:12345 &
+sleep 1
+
+CLIENT="ip netns exec proxy-rs-client"
+$CLIENT curl -sf -o /dev/null http://10.0.2.2:8080/
+echo "tcp ok"
+$CLIENT python3 -c '
+import socket
+s = socket.socket(socket.AF_INET, socket.SOCK_DGRAM)
+s.settimeout(3)
+s.sendto(b"ping", ("10.0.2.2", 9000))
+data, addr = s.recvfrom(65535)
+assert data == b"ping" and addr == ("10.0.2.2", 9000), (data, addr)
+'
+echo "udp ok"
@@ -0,0 +1,443 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// An IP network, `ip/prefix`, or a single address.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Cidr {
+    ip: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn parse(s: &str) -> Option<Cidr> {
+        let mut parts = s.splitn(2, '/');
+        let ip: IpAddr = parts.next()?.parse().ok()?;
+        let max = if ip.is_ipv4() { 32 } else { 128 };
+        let prefix = match parts.next() {
+            None => max,
+            Some(prefix) => prefix.parse().ok()?,
+        };
+        if prefix > max {
+            return None;
+        }
+        Some(Cidr { ip, prefix })
+    }
+
+    /// Whether `ip` is in the network, IPv4-mapped IPv6 addresses counting
+    /// as the IPv4 ones they map.
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.ip, unmap(ip)) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                prefix_eq(&network.octets(), &ip.octets(), self.prefix)
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                prefix_eq(&network.octets(), &ip.octets(), self.prefix)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// The IPv4 address an IPv4-mapped IPv6 one maps, or `ip` itself.
+pub fn unmap(ip: &IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => match v6.segments() {
+            [0, 0, 0, 0, 0, 0xffff, _, _] => {
+                let octets = v6.octets();
+                IpAddr::V4(Ipv4Addr::new(
+                    octets[12], octets[13], octets[14], octets[15],
+                ))
+            }
+            _ => *ip,
+        },
+        IpAddr::V4(_) => *ip,
+    }
+}
+
+/// Parses `port[-port]` into the first and last port.
+pub fn parse_ports(ports: &str) -> Option<(u16, u16)> {
+    let mut parts = ports.splitn(2, '-');
+    let low = parts.next()?.parse().ok()?;
+    let high = match parts.next() {
+        None => low,
+        Some(high) => high.parse().ok()?,
+    };
+    if low > high {
+        return None;
+    }
+    Some((low, high))
+}
+
+fn prefix_eq(a: &[u8], b: &[u8], prefix: u8) -> bool {
+    let bytes = prefix as usize / 8;
+    let bits = prefix % 8;
+    if a[..bytes] != b[..bytes] {
+        return false;
+    }
+    if bits == 0 {
+        return true;
+    }
+    let mask = 0xff_u8 << (8 - bits);
+    a[bytes] & mask == b[bytes] & mask
+}
+
+/// Ranges targets may not be in unless allowed: unspecified, private,
+/// shared, loopback, link-local, multicast and reserved addresses.
+fn non_public() -> Vec<Cidr> {
+    let v4 = |a, b, c, d, prefix| Cidr {
+        ip: IpAddr::V4(Ipv4Addr::new(a, b, c, d)),
+        prefix,
+    };
+    let v6 = |first, prefix| Cidr {
+        ip: IpAddr::V6(Ipv6Addr::new(first, 0, 0, 0, 0, 0, 0, 0)),
+        prefix,
+    };
+    vec![
+        v4(0, 0, 0, 0, 8),
+        v4(10, 0, 0, 0, 8),
+        v4(100, 64, 0, 0, 10),
+        v4(127, 0, 0, 0, 8),
+        v4(169, 254, 0, 0, 16),
+        v4(172, 16, 0, 0, 12),
+        v4(192, 168, 0, 0, 16),
+        v4(224, 0, 0, 0, 4),
+        v4(240, 0, 0, 0, 4),
+        // unspecified and loopback
+        v6(0, 127),
+        v6(0xfc00, 7),
+        v6(0xfe80, 10),
+        v6(0xff00, 8),
+    ]
+}
+
+/// Which targets the remote connects to and relays datagrams for. A denied
+/// CIDR or port always wins, an allowed CIDR lifts the default denial of
+/// non-public ranges, and once some ports are allowed no other port is.
+pub struct Egress {
+    allow: Vec<Cidr>,
+    deny: Vec<Cidr>,
+    allow_ports: Vec<(u16, u16)>,
+    deny_ports: Vec<(u16, u16)>,
+    non_public: Vec<Cidr>,
+}
+
+impl Egress {
+    pub fn new(
+        allow: Vec<Cidr>,
+        deny: Vec<Cidr>,
+        allow_ports: Vec<(u16, u16)>,
+        deny_ports: Vec<(u16, u16)>,
+    ) -> Egress {
+        Egress {
+            allow,
+            deny,
+            allow_ports,
+            deny_ports,
+            non_public: non_public(),
+        }
+    }
+
+    pub fn allowed(&self, addr: &SocketAddr) -> bool {
+        let port = addr.port();
+        let in_ports = |ports: &[(u16, u16)]| {
+            ports
+                .iter()
+                .any(|(low, high)| *low <= port && port <= *high)
+        };
+        if in_ports(&self.deny_ports) {
+            return false;
+        }
+        if !self.allow_ports.is_empty() && !in_ports(&self.allow_ports) {
+            return false;
+        }
+        let ip = addr.ip();
+        let in_cidrs = |cidrs: &[Cidr]| cidrs.iter().any(|cidr| cidr.contains(&ip));
+        !in_cidrs(&self.deny) && (in_cidrs(&self.allow) || !in_cidrs(&self.non_public))
+    }
+}
+
+// Most addresses whose failures are counted at once, the one counted the
+// longest giving way to a new one.
+const MAX_FAILING: usize = 4096;
+
+/// Temporary bans of client addresses that keep failing the handshake, kept
+/// in an optional file so that they outlive restarts.
+pub struct Bans {
+    // failures within `window` that get an address banned, none when 0
+    max_failures: u32,
+    window: Duration,
+    ban_time: Duration,
+    // bans to write, the file being written by a thread of its own
+    saver: Option<mpsc::Sender<String>>,
+    // failure count and the time of the first in the window
+    failures: HashMap<IpAddr, (u32, Instant)>,
+    banned: HashMap<IpAddr, SystemTime>,
+}
+
+impl Bans {
+    /// Reads the bans still running from `file`, lines of `ip unix-time`.
+    pub fn new(
+        max_failures: u32,
+        window: Duration,
+        ban_time: Duration,
+        file: Option<String>,
+    ) -> Bans {
+        let mut banned = HashMap::new();
+        if let Some(file) = &file {
+            match fs::read_to_string(file) {
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                Err(err) => warn!("read {} {:?}", file, err),
+                Ok(text) => {
+                    for line in text.lines() {
+                        let mut words = line.split_whitespace();
+                        let ban = (
+                            words.next().and_then(|ip| ip.parse::<IpAddr>().ok()),
+                            words.next().and_then(|secs| secs.parse().ok()),
+                        );
+                        if let (Some(ip), Some(secs)) = ban {
+                            banned.insert(ip, UNIX_EPOCH + Duration::from_secs(secs));
+                        }
+                    }
+                }
+            }
+        }
+        let saver = file.map(|file| {
+            let (tx, rx) = mpsc::channel::<String>();
+            thread::spawn(move || {
+                while let Ok(mut text) = rx.recv() {
+                    // only the latest list matters
+                    while let Ok(newer) = rx.try_recv() {
+                        text = newer;
+                    }
+                    if let Err(err) = save(&file, &text) {
+                        warn!("save bans {} {:?}", file, err);
+                    }
+                }
+            });
+            tx
+        });
+        let mut bans = Bans {
+            max_failures,
+            window,
+            ban_time,
+            saver,
+            failures: HashMap::new(),
+            banned,
+        };
+        bans.expire();
+        bans
+    }
+
+    fn expire(&mut self) {
+        let now = SystemTime::now();
+        self.banned.retain(|_, until| *until > now);
+        let window = self.window;
+        self.failures
+            .retain(|_, (_, first)| first.elapsed() < window);
+    }
+
+    pub fn banned(&mut self, ip: &IpAddr) -> bool {
+        match self.banned.get(ip) {
+            Some(until) if *until > SystemTime::now() => true,
+            Some(_) => {
+                self.banned.remove(ip);
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Counts a failed handshake from `ip`, banning it once there are too
+    /// many.
+    pub fn fail(&mut self, ip: IpAddr) {
+        if self.max_failures == 0 {
+            return;
+        }
+        self.expire();
+        if self.failures.len() >= MAX_FAILING && !self.failures.contains_key(&ip) {
+            let oldest = self
+                .failures
+                .iter()
+                .min_by_key(|(_, (_, first))| *first)
+                .map(|(ip, _)| *ip);
+            if let Some(oldest) = oldest {
+                self.failures.remove(&oldest);
+            }
+        }
+        let failures = self.failures.entry(ip).or_insert((0, Instant::now()));
+        failures.0 += 1;
+        if failures.0 < self.max_failures {
+            return;
+        }
+        warn!(
+            "ban {} for {}s after {} failures",
+            ip,
+            self.ban_time.as_secs(),
+            failures.0
+        );
+        self.failures.remove(&ip);
+        self.banned.insert(ip, SystemTime::now() + self.ban_time);
+        self.save();
+    }
+
+    /// Hands the bans over to the thread writing the file.
+    fn save(&self) {
+        let saver = match &self.saver {
+            None => return,
+            Some(saver) => saver,
+        };
+        let mut text = String::new();
+        for (ip, until) in &self.banned {
+            let secs = until
+                .duration_since(UNIX_EPOCH)
+                .map(|until| until.as_secs())
+                .unwrap_or(0);
+            text.push_str(&format!("{} {}\n", ip, secs));
+        }
+        if saver.send(text).is_err() {
+            warn!("save bans, writer gone");
+        }
+    }
+}
+
+fn save(file: &str, text: &str) -> io::Result<()> {
+    // replaced whole, so that a crash leaves the old list
+    let tmp = format!("{}.tmp", file);
+    fs::write(&tmp, text)?;
+    fs::rename(&tmp, file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cidrs(cidrs: &[&str]) -> Vec<Cidr> {
+        cidrs
+            .iter()
+            .map(|cidr| Cidr::parse(cidr).unwrap())
+            .collect()
+    }
+
+    fn addr(addr: &str) -> SocketAddr {
+        addr.parse().unwrap()
+    }
+
+    #[test]
+    fn cidr() {
+        let cidr = Cidr::parse("10.1.0.0/15").unwrap();
+        assert!(cidr.contains(&"10.0.255.255".parse().unwrap()));
+        assert!(cidr.contains(&"10.1.2.3".parse().unwrap()));
+        assert!(!cidr.contains(&"10.2.0.0".parse().unwrap()));
+        assert!(cidr.contains(&"::ffff:10.1.2.3".parse().unwrap()));
+        assert!(!cidr.contains(&"2001:db8::1".parse().unwrap()));
+
+        let cidr = Cidr::parse("2001:db8::/32").unwrap();
+        assert!(cidr.contains(&"2001:db8:ffff::1".parse().unwrap()));
+        assert!(!cidr.contains(&"2001:db9::1".parse().unwrap()));
+        assert!(!cidr.contains(&"10.1.2.3".parse().unwrap()));
+
+        let single = Cidr::parse("192.0.2.1").unwrap();
+        assert!(single.contains(&"192.0.2.1".parse().unwrap()));
+        assert!(!single.contains(&"192.0.2.2".parse().unwrap()));
+        assert!(Cidr::parse("0.0.0.0/0")
+            .unwrap()
+            .contains(&"203.0.113.9".parse().unwrap()));
+
+        for cidr in &[
+            "10.0.0.0/33",
+            "::/129",
+            "10.0.0/8",
+            "10.0.0.0/",
+            "host/8",
+            "",
+        ] {
+            assert!(Cidr::parse(cidr).is_none(), "{}", cidr);
+        }
+    }
+
+    #[test]
+    fn ports() {
+        assert_eq!(parse_ports("80"), Some((80, 80)));
+        assert_eq!(parse_ports("1000-2000"), Some((1000, 2000)));
+        assert_eq!(parse_ports("2000-1000"), None);
+        assert_eq!(parse_ports("65536"), None);
+        assert_eq!(parse_ports("-1"), None);
+    }
+
+    #[test]
+    fn egress_default() {
+        let egress = Egress::new(vec![], vec![], vec![], vec![]);
+        assert!(egress.allowed(&addr("203.0.113.9:443")));
+        assert!(egress.allowed(&addr("[2001:db8::1]:443")));
+        for target in &[
+            "0.0.0.0:80",
+            "10.0.0.1:80",
+            "100.64.0.1:80",
+            "127.0.0.1:80",
+            "169.254.169.254:80",
+            "172.31.0.1:80",
+            "192.168.1.1:80",
+            "224.0.0.1:80",
+            "255.255.255.255:80",
+            "[::]:80",
+            "[::1]:80",
+            "[::ffff:127.0.0.1]:80",
+            "[fd00::1]:80",
+            "[fe80::1]:80",
+            "[ff02::1]:80",
+        ] {
+            assert!(!egress.allowed(&addr(target)), "{}", target);
+        }
+    }
+
+    #[test]
+    fn egress_policy() {
+        let egress = Egress::new(
+            cidrs(&["10.0.0.0/8"]),
+            cidrs(&["10.9.0.0/16", "198.51.100.0/24"]),
+            vec![(80, 80), (443, 443), (8000, 8999)],
+            vec![(8080, 8080)],
+        );
+        assert!(egress.allowed(&addr("10.1.2.3:443")));
+        assert!(!egress.allowed(&addr("10.9.2.3:443")));
+        assert!(!egress.allowed(&addr("198.51.100.1:80")));
+        assert!(!egress.allowed(&addr("192.168.1.1:80")));
+        assert!(egress.allowed(&addr("203.0.113.9:8443")));
+        assert!(!egress.allowed(&addr("203.0.113.9:8080")));
+        assert!(!egress.allowed(&addr("203.0.113.9:22")));
+    }
+
+    #[test]
+    fn bans_bounded() {
+        let mut bans = Bans::new(3, Duration::from_secs(60), Duration::from_secs(60), None);
+        let ip = |i: usize| IpAddr::from([10, (i >> 16) as u8, (i >> 8) as u8, i as u8]);
+        for i in 0..MAX_FAILING + 100 {
+            bans.fail(ip(i));
+        }
+        assert_eq!(bans.failures.len(), MAX_FAILING);
+        assert!(!bans.failures.contains_key(&ip(0)));
+        let last = ip(MAX_FAILING + 99);
+        bans.fail(last);
+        assert!(!bans.banned(&last));
+        bans.fail(last);
+        assert!(bans.banned(&last));
+        assert!(!bans.failures.contains_key(&last));
+    }
+
+    #[test]
+    fn failures_expire() {
+        let mut bans = Bans::new(2, Duration::from_millis(50), Duration::from_secs(60), None);
+        let ip = IpAddr::from([192, 0, 2, 1]);
+        bans.fail(ip);
+        thread::sleep(Duration::from_millis(60));
+        bans.fail(ip);
+        assert!(!bans.banned(&ip));
+        bans.fail(ip);
+        assert!(bans.banned(&ip));
+    }
+}
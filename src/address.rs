@@ -0,0 +1,169 @@
+use bytes::{Buf, BufMut};
+use std::fmt;
+use std::io;
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::net::TcpStream;
+use tokio::prelude::*;
+
+pub const ATYP_IPV4: u8 = 0x01;
+pub const ATYP_DOMAIN: u8 = 0x03;
+pub const ATYP_IPV6: u8 = 0x04;
+
+/// Connect target, encoded on the wire as SOCKS5 `ATYP DST.ADDR DST.PORT`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Address {
+    Ip(SocketAddr),
+    Domain(String, u16),
+}
+
+impl Address {
+    pub fn unspecified() -> Address {
+        Address::Ip(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0))
+    }
+
+    pub fn port(&self) -> u16 {
+        match self {
+            Address::Ip(addr) => addr.port(),
+            Address::Domain(_, port) => *port,
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buffer = vec![];
+        match self {
+            Address::Ip(SocketAddr::V4(addr)) => {
+                buffer.put_u8(ATYP_IPV4);
+                buffer.put_slice(&addr.ip().octets());
+            }
+            Address::Ip(SocketAddr::V6(addr)) => {
+                buffer.put_u8(ATYP_IPV6);
+                buffer.put_slice(&addr.ip().octets());
+            }
+            Address::Domain(host, _) => {
+                buffer.put_u8(ATYP_DOMAIN);
+                buffer.put_u8(host.len() as u8);
+                buffer.put_slice(host.as_bytes());
+            }
+        }
+        buffer.put_u16(self.port());
+        buffer
+    }
+
+    /// Parses an address from the front of `buf`, returning it together with
+    /// the number of bytes consumed.
+    pub fn from_bytes(buf: &[u8]) -> io::Result<(Address, usize)> {
+        let short = || Error::new(ErrorKind::UnexpectedEof, "address truncated");
+        let atyp = *buf.first().ok_or_else(short)?;
+        let (ip, offset) = match atyp {
+            ATYP_IPV4 => {
+                let ip = buf.get(1..5).ok_or_else(short)?;
+                (IpAddr::V4(Ipv4Addr::new(ip[0], ip[1], ip[2], ip[3])), 5)
+            }
+            ATYP_IPV6 => {
+                let mut ip = [0_u8; 16];
+                ip.copy_from_slice(buf.get(1..17).ok_or_else(short)?);
+                (IpAddr::V6(Ipv6Addr::from(ip)), 17)
+            }
+            ATYP_DOMAIN => {
+                let host_len = *buf.get(1).ok_or_else(short)? as usize;
+                let host = buf.get(2..2 + host_len).ok_or_else(short)?;
+                let host = match std::str::from_utf8(host) {
+                    Err(err) => return Err(Error::new(ErrorKind::InvalidData, err)),
+                    Ok(host) => host.to_string(),
+                };
+                let offset = 2 + host_len;
+                let port = buf.get(offset..offset + 2).ok_or_else(short)?.get_u16();
+                return Ok((Address::Domain(host, port), offset + 2));
+            }
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("unknown address type {:#04x}", atyp),
+                ))
+            }
+        };
+        let port = buf.get(offset..offset + 2).ok_or_else(short)?.get_u16();
+        Ok((Address::Ip(SocketAddr::new(ip, port)), offset + 2))
+    }
+
+    /// Reads `ATYP DST.ADDR DST.PORT` from a SOCKS5 client.
+    pub async fn read_from(s: &mut TcpStream) -> io::Result<Address> {
+        let mut atyp = [0_u8; 2];
+        s.read_exact(&mut atyp[..1]).await?;
+        let len = match atyp[0] {
+            ATYP_IPV4 => 1 + 4 + 2,
+            ATYP_IPV6 => 1 + 16 + 2,
+            ATYP_DOMAIN => {
+                s.read_exact(&mut atyp[1..]).await?;
+                2 + atyp[1] as usize + 2
+            }
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("unknown address type {:#04x}", atyp[0]),
+                ))
+            }
+        };
+        let mut buf = vec![0_u8; len];
+        let offset = if atyp[0] == ATYP_DOMAIN { 2 } else { 1 };
+        buf[..offset].copy_from_slice(&atyp[..offset]);
+        s.read_exact(&mut buf[offset..]).await?;
+        Ok(Address::from_bytes(&buf)?.0)
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Address::Ip(addr) => write!(f, "{}", addr),
+            Address::Domain(host, port) => write!(f, "{}:{}", host, port),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_bytes_round_trip() {
+        let addresses = [
+            Address::Ip("192.0.2.1:443".parse().unwrap()),
+            Address::Ip("[2001:db8::1]:8080".parse().unwrap()),
+            Address::Domain("example.com".to_string(), 80),
+            Address::Domain(String::new(), 1),
+        ];
+        for address in addresses.iter() {
+            let mut bytes = address.to_bytes();
+            let len = bytes.len();
+            // early data after the address is not consumed
+            bytes.extend_from_slice(b"data");
+            assert_eq!(Address::from_bytes(&bytes).unwrap(), (address.clone(), len));
+        }
+    }
+
+    #[test]
+    fn from_bytes_truncated() {
+        let addresses = [
+            Address::Ip("192.0.2.1:443".parse().unwrap()),
+            Address::Ip("[2001:db8::1]:8080".parse().unwrap()),
+            Address::Domain("example.com".to_string(), 80),
+        ];
+        for address in addresses.iter() {
+            let bytes = address.to_bytes();
+            for len in 0..bytes.len() {
+                let err = Address::from_bytes(&bytes[..len]).unwrap_err();
+                assert_eq!(err.kind(), ErrorKind::UnexpectedEof, "{} {}", address, len);
+            }
+        }
+    }
+
+    #[test]
+    fn from_bytes_invalid() {
+        let err = Address::from_bytes(&[0x02, 0, 0, 0, 0, 0, 0]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        let err = Address::from_bytes(&[ATYP_DOMAIN, 2, 0xff, 0xfe, 0, 80]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+}
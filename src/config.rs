@@ -1,21 +1,112 @@
+use crate::acl::{parse_ports, Cidr, Egress};
+use crate::address::Address;
+use crate::http;
+use crate::outbound::{Outbound, Pick};
+use crate::resolver;
+use crate::rules::{Action, Rule, Rules};
 use openssl::symm::Cipher;
+use std::collections::HashMap;
 use std::error::Error;
-use std::net::ToSocketAddrs;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
 
 pub struct Config<'a> {
     pub mode: &'a str,
     pub listen: &'a str,
     pub remote_addr: &'a str,
     pub key: &'a str,
+    pub users: Vec<&'a str>,
+    pub fast_open: bool,
+    pub early_data_timeout: Option<&'a str>,
+    pub http_listen: Option<&'a str>,
+    pub redir_listen: Option<&'a str>,
+    pub tproxy_listen: Option<&'a str>,
+    pub sniff_ports: Option<&'a str>,
+    pub sniff_override: bool,
+    pub tunnels: Vec<&'a str>,
+    pub reverses: Vec<&'a str>,
+    pub reverse_user: Option<&'a str>,
+    pub reverse_users: Vec<&'a str>,
+    pub reverse_allow: Vec<&'a str>,
+    pub dns_listen: Option<&'a str>,
+    pub dns_upstream: Option<&'a str>,
+    pub dns_direct: Vec<&'a str>,
+    pub dns_direct_upstream: Option<&'a str>,
+    pub resolvers: Vec<&'a str>,
+    pub hosts_file: Option<&'a str>,
+    pub resolve_timeout: Option<&'a str>,
+    pub resolve_prefer: Option<&'a str>,
+    pub bind_v4: Vec<&'a str>,
+    pub bind_v6: Vec<&'a str>,
+    pub bind_pick: Option<&'a str>,
+    pub bind_device: Option<&'a str>,
+    pub mark: Option<&'a str>,
+    pub user_outbounds: Vec<&'a str>,
+    pub allow_cidrs: Vec<&'a str>,
+    pub deny_cidrs: Vec<&'a str>,
+    pub allow_ports: Vec<&'a str>,
+    pub deny_ports: Vec<&'a str>,
+    pub allow_clients: Vec<&'a str>,
+    pub ban_after: Option<&'a str>,
+    pub ban_window: Option<&'a str>,
+    pub ban_time: Option<&'a str>,
+    pub ban_file: Option<&'a str>,
+    pub rules: Vec<&'a str>,
+    pub rule_files: Vec<&'a str>,
+    pub rule_default: Option<&'a str>,
 }
 
 impl<'a> Config<'a> {
-    pub fn new_local_server(listen: &'a str, remote_addr: &'a str, key: &'a str) -> Config<'a> {
+    pub fn new_local_server(
+        listen: &'a str,
+        remote_addr: &'a str,
+        key: &'a str,
+        users: Vec<&'a str>,
+        fast_open: bool,
+    ) -> Config<'a> {
         Config {
             mode: "local",
             listen,
             remote_addr,
             key,
+            users,
+            fast_open,
+            early_data_timeout: None,
+            http_listen: None,
+            redir_listen: None,
+            tproxy_listen: None,
+            sniff_ports: None,
+            sniff_override: false,
+            tunnels: vec![],
+            reverses: vec![],
+            reverse_user: None,
+            reverse_users: vec![],
+            reverse_allow: vec![],
+            dns_listen: None,
+            dns_upstream: None,
+            dns_direct: vec![],
+            dns_direct_upstream: None,
+            resolvers: vec![],
+            hosts_file: None,
+            resolve_timeout: None,
+            resolve_prefer: None,
+            bind_v4: vec![],
+            bind_v6: vec![],
+            bind_pick: None,
+            bind_device: None,
+            mark: None,
+            user_outbounds: vec![],
+            allow_cidrs: vec![],
+            deny_cidrs: vec![],
+            allow_ports: vec![],
+            deny_ports: vec![],
+            allow_clients: vec![],
+            ban_after: None,
+            ban_window: None,
+            ban_time: None,
+            ban_file: None,
+            rules: vec![],
+            rule_files: vec![],
+            rule_default: None,
         }
     }
     pub fn new_remote_server(listen: &'a str, key: &'a str) -> Config<'a> {
@@ -24,6 +115,45 @@ impl<'a> Config<'a> {
             listen,
             remote_addr: "",
             key,
+            users: vec![],
+            fast_open: false,
+            early_data_timeout: None,
+            http_listen: None,
+            redir_listen: None,
+            tproxy_listen: None,
+            sniff_ports: None,
+            sniff_override: false,
+            tunnels: vec![],
+            reverses: vec![],
+            reverse_user: None,
+            reverse_users: vec![],
+            reverse_allow: vec![],
+            dns_listen: None,
+            dns_upstream: None,
+            dns_direct: vec![],
+            dns_direct_upstream: None,
+            resolvers: vec![],
+            hosts_file: None,
+            resolve_timeout: None,
+            resolve_prefer: None,
+            bind_v4: vec![],
+            bind_v6: vec![],
+            bind_pick: None,
+            bind_device: None,
+            mark: None,
+            user_outbounds: vec![],
+            allow_cidrs: vec![],
+            deny_cidrs: vec![],
+            allow_ports: vec![],
+            deny_ports: vec![],
+            allow_clients: vec![],
+            ban_after: None,
+            ban_window: None,
+            ban_time: None,
+            ban_file: None,
+            rules: vec![],
+            rule_files: vec![],
+            rule_default: None,
         }
     }
 
@@ -41,6 +171,109 @@ impl<'a> Config<'a> {
                 if let Err(err) = self.remote_addr.to_socket_addrs() {
                     return Err(format!("`remote-addr` parameter error {}", err).into());
                 }
+                if let Some(Err(err)) = self.early_data_timeout.map(|ms| ms.parse::<u64>()) {
+                    return Err(format!("`early-data-timeout` parameter error {}", err).into());
+                }
+                if let Some(Err(err)) = self.http_listen.map(|listen| listen.to_socket_addrs()) {
+                    return Err(format!("`http-listen` parameter error {}", err).into());
+                }
+                if let Some(Err(err)) = self.redir_listen.map(|listen| listen.to_socket_addrs()) {
+                    return Err(format!("`redir-listen` parameter error {}", err).into());
+                }
+                if let Some(Err(err)) = self
+                    .tproxy_listen
+                    .map(|listen| listen.parse::<SocketAddr>())
+                {
+                    return Err(format!("`tproxy-listen` parameter error {}", err).into());
+                }
+                if let Some(ports) = self.sniff_ports {
+                    let mut ranges = ports.split(',').filter(|range| !range.is_empty());
+                    if ranges.any(|range| parse_ports(range).is_none()) {
+                        return Err(format!("`sniff-ports` parameter error {:?}", ports).into());
+                    }
+                }
+                for tunnel in &self.tunnels {
+                    let mut parts = tunnel.splitn(2, '=');
+                    let listen = parts.next().unwrap_or("");
+                    if let Err(err) = listen.to_socket_addrs() {
+                        return Err(format!("`tunnel` parameter error {:?} {}", tunnel, err).into());
+                    }
+                    match parts
+                        .next()
+                        .and_then(|target| http::parse_authority(target, 0))
+                    {
+                        Some(target) if target.port() != 0 => {}
+                        _ => return Err(format!("`tunnel` parameter error {:?}", tunnel).into()),
+                    }
+                }
+                for reverse in &self.reverses {
+                    let mut parts = reverse.splitn(2, '=');
+                    if let Err(err) = parts.next().unwrap_or("").parse::<SocketAddr>() {
+                        return Err(
+                            format!("`reverse` parameter error {:?} {}", reverse, err).into()
+                        );
+                    }
+                    match parts
+                        .next()
+                        .and_then(|target| http::parse_authority(target, 0))
+                    {
+                        Some(target) if target.port() != 0 => {}
+                        _ => return Err(format!("`reverse` parameter error {:?}", reverse).into()),
+                    }
+                }
+                if let Some(Err(err)) = self.dns_listen.map(|listen| listen.to_socket_addrs()) {
+                    return Err(format!("`dns-listen` parameter error {}", err).into());
+                }
+                if let Some(upstream) = self.dns_upstream {
+                    if http::parse_authority(upstream, 53).is_none() {
+                        return Err(format!("`dns-upstream` parameter error {:?}", upstream).into());
+                    }
+                }
+                match self
+                    .dns_direct_upstream
+                    .map(|upstream| upstream.parse::<SocketAddr>())
+                {
+                    Some(Err(err)) => {
+                        return Err(format!("`dns-direct-upstream` parameter error {}", err).into());
+                    }
+                    None if !self.dns_direct.is_empty() => {
+                        return Err("`dns-direct` needs `dns-direct-upstream`".into());
+                    }
+                    _ => {}
+                }
+                match self.reverse_user {
+                    Some(user) if credentials(user).is_none() => {
+                        return Err(format!("`reverse-user` parameter error {:?}", user).into());
+                    }
+                    _ => {}
+                }
+                for user in &self.users {
+                    match user.find(':') {
+                        Some(i) if i > 0 && i <= 255 && user.len() - i - 1 <= 255 => {}
+                        _ => return Err(format!("`user` parameter error {:?}", user).into()),
+                    }
+                }
+                for rule in &self.rules {
+                    if Rule::parse(rule, None).is_none() {
+                        return Err(format!("`rule` parameter error {:?}", rule).into());
+                    }
+                }
+                for file in &self.rule_files {
+                    match file.rfind('=') {
+                        Some(i) if Action::parse(&file[i + 1..]).is_none() => {
+                            return Err(format!("`rule-file` parameter error {:?}", file).into());
+                        }
+                        _ => {}
+                    }
+                }
+                if let Some(action) = self.rule_default {
+                    if Action::parse(action).is_none() {
+                        return Err(format!("`rule-default` parameter error {:?}", action).into());
+                    }
+                }
+                if let Some(Err(err)) = self.mark.map(|mark| mark.parse::<u32>()) {
+                    return Err(format!("`direct-mark` parameter error {}", err).into());
+                }
                 Ok(())
             }
             "remote" => {
@@ -50,9 +283,257 @@ impl<'a> Config<'a> {
                 if let Err(err) = self.listen.to_socket_addrs() {
                     return Err(format!("`listen` parameter error {}", err).into());
                 }
+                if self.reverse_allow().len() != self.reverse_allow.len() {
+                    return Err("`reverse-allow` parameter error".into());
+                }
+                for user in &self.reverse_users {
+                    if credentials(user).is_none() {
+                        return Err(format!("`reverse-user` parameter error {:?}", user).into());
+                    }
+                }
+                if self.resolvers().len() != self.resolvers.len() {
+                    return Err("`resolver` parameter error".into());
+                }
+                match self.resolve_timeout.map(|secs| secs.parse::<u64>()) {
+                    Some(Ok(0)) => return Err("`resolve-timeout` parameter error 0".into()),
+                    Some(Err(err)) => {
+                        return Err(format!("`resolve-timeout` parameter error {}", err).into())
+                    }
+                    _ => {}
+                }
+                for ip in &self.bind_v4 {
+                    if let Err(err) = ip.parse::<Ipv4Addr>() {
+                        return Err(format!("`bind-v4` parameter error {:?} {}", ip, err).into());
+                    }
+                }
+                for ip in &self.bind_v6 {
+                    if let Err(err) = ip.parse::<Ipv6Addr>() {
+                        return Err(format!("`bind-v6` parameter error {:?} {}", ip, err).into());
+                    }
+                }
+                if let Some(pick) = self.bind_pick {
+                    if Pick::parse(pick).is_none() {
+                        return Err(format!("`bind-pick` parameter error {:?}", pick).into());
+                    }
+                }
+                match self.bind_device {
+                    Some(device) if device.is_empty() || device.len() >= libc::IFNAMSIZ => {
+                        return Err(format!("`bind-device` parameter error {:?}", device).into());
+                    }
+                    _ => {}
+                }
+                if let Some(Err(err)) = self.mark.map(|mark| mark.parse::<u32>()) {
+                    return Err(format!("`mark` parameter error {}", err).into());
+                }
+                if self.user_outbounds().len() != self.user_outbounds.len() {
+                    return Err("`user-outbound` parameter error".into());
+                }
+                for (name, cidrs) in &[
+                    ("allow-cidr", &self.allow_cidrs),
+                    ("deny-cidr", &self.deny_cidrs),
+                    ("allow-client", &self.allow_clients),
+                ] {
+                    if let Some(cidr) = cidrs.iter().find(|cidr| Cidr::parse(cidr).is_none()) {
+                        return Err(format!("`{}` parameter error {:?}", name, cidr).into());
+                    }
+                }
+                for (name, ports) in &[
+                    ("allow-port", &self.allow_ports),
+                    ("deny-port", &self.deny_ports),
+                ] {
+                    if let Some(port) = ports.iter().find(|port| parse_ports(port).is_none()) {
+                        return Err(format!("`{}` parameter error {:?}", name, port).into());
+                    }
+                }
+                for (name, value) in &[
+                    ("ban-after", self.ban_after),
+                    ("ban-window", self.ban_window),
+                    ("ban-time", self.ban_time),
+                ] {
+                    if let Some(Err(err)) = value.map(|value| value.parse::<u32>()) {
+                        return Err(format!("`{}` parameter error {}", name, err).into());
+                    }
+                }
+                if let Some(prefer) = self.resolve_prefer {
+                    if resolver::Prefer::parse(prefer).is_none() {
+                        return Err(format!("`resolve-prefer` parameter error {:?}", prefer).into());
+                    }
+                }
                 Ok(())
             }
             _ => unreachable!(),
         }
     }
+
+    /// Ports whose transparent connections are sniffed for a host name, the
+    /// first and last of each range.
+    pub fn sniff_ports(&self) -> Vec<(u16, u16)> {
+        self.sniff_ports
+            .unwrap_or("")
+            .split(',')
+            .filter_map(parse_ports)
+            .collect()
+    }
+
+    /// Listen addresses of the port-forward tunnels, with their targets.
+    pub fn tunnels(&self) -> Vec<(String, Address)> {
+        self.tunnels
+            .iter()
+            .filter_map(|tunnel| {
+                let mut parts = tunnel.splitn(2, '=');
+                let listen = parts.next()?.to_string();
+                Some((listen, http::parse_authority(parts.next()?, 0)?))
+            })
+            .collect()
+    }
+
+    /// Remote listen addresses of the reverse tunnels, with their local
+    /// targets.
+    pub fn reverses(&self) -> Vec<(SocketAddr, Address)> {
+        self.reverses
+            .iter()
+            .filter_map(|reverse| {
+                let mut parts = reverse.splitn(2, '=');
+                let listen = parts.next()?.parse().ok()?;
+                Some((listen, http::parse_authority(parts.next()?, 0)?))
+            })
+            .collect()
+    }
+
+    /// Name and password reverse tunnels are opened as.
+    pub fn reverse_user(&self) -> Option<(String, String)> {
+        self.reverse_user.and_then(credentials)
+    }
+
+    /// Passwords of the users that may open reverse tunnels, by name.
+    pub fn reverse_users(&self) -> HashMap<String, String> {
+        self.reverse_users
+            .iter()
+            .filter_map(|user| credentials(user))
+            .collect()
+    }
+
+    /// Users allowed to open reverse tunnels, with the first and last port
+    /// each may listen on. `*` stands for any user, `-` for anonymous ones.
+    pub fn reverse_allow(&self) -> Vec<(String, u16, u16)> {
+        self.reverse_allow
+            .iter()
+            .filter_map(|allow| {
+                let mut parts = allow.splitn(2, ':');
+                let user = parts.next()?;
+                let (low, high) = parse_ports(parts.next()?)?;
+                if user.is_empty() {
+                    return None;
+                }
+                Some((user.to_string(), low, high))
+            })
+            .collect()
+    }
+
+    /// Networks clients may connect from, any when empty.
+    pub fn allow_clients(&self) -> Vec<Cidr> {
+        self.allow_clients
+            .iter()
+            .filter_map(|cidr| Cidr::parse(cidr))
+            .collect()
+    }
+
+    /// Destination policy of connections to targets.
+    pub fn egress(&self) -> Egress {
+        let cidrs = |cidrs: &[&str]| cidrs.iter().filter_map(|cidr| Cidr::parse(cidr)).collect();
+        let ports = |ports: &[&str]| ports.iter().filter_map(|port| parse_ports(port)).collect();
+        Egress::new(
+            cidrs(&self.allow_cidrs),
+            cidrs(&self.deny_cidrs),
+            ports(&self.allow_ports),
+            ports(&self.deny_ports),
+        )
+    }
+
+    /// Upstream name servers, `ip[:port]`, port 53 by default.
+    pub fn resolvers(&self) -> Vec<SocketAddr> {
+        self.resolvers
+            .iter()
+            .filter_map(|resolver| match resolver.parse() {
+                Ok(addr) => Some(addr),
+                Err(_) => Some(SocketAddr::new(resolver.parse().ok()?, 53)),
+            })
+            .collect()
+    }
+
+    /// Outbound settings for every user.
+    pub fn outbound(&self) -> Outbound {
+        let mut outbound = Outbound::default();
+        outbound.bind_v4 = self
+            .bind_v4
+            .iter()
+            .filter_map(|ip| ip.parse().ok())
+            .collect();
+        outbound.bind_v6 = self
+            .bind_v6
+            .iter()
+            .filter_map(|ip| ip.parse().ok())
+            .collect();
+        outbound.pick = self.bind_pick.and_then(Pick::parse);
+        outbound.device = self.bind_device.map(|device| device.to_string());
+        outbound.mark = self.mark.and_then(|mark| mark.parse().ok());
+        outbound
+    }
+
+    /// Outbound settings of particular users, `-` standing for anonymous
+    /// ones.
+    pub fn user_outbounds(&self) -> Vec<(String, Outbound)> {
+        self.user_outbounds
+            .iter()
+            .filter_map(|user_outbound| {
+                let mut parts = user_outbound.splitn(2, ':');
+                let user = parts.next()?;
+                if user.is_empty() {
+                    return None;
+                }
+                Some((user.to_string(), Outbound::parse(parts.next()?)?))
+            })
+            .collect()
+    }
+
+    /// Routing rules, those given inline first, then those of each file in
+    /// turn. Reads the files.
+    pub fn rules(&self) -> Result<Rules, Box<dyn Error>> {
+        let mut rules: Vec<Rule> = self
+            .rules
+            .iter()
+            .filter_map(|rule| Rule::parse(rule, None))
+            .collect();
+        for file in &self.rule_files {
+            match file.rfind('=') {
+                None => rules.extend(Rule::load(file, None)?),
+                Some(i) => rules.extend(Rule::load(&file[..i], Action::parse(&file[i + 1..]))?),
+            }
+        }
+        let default = self
+            .rule_default
+            .and_then(Action::parse)
+            .unwrap_or(Action::Proxy);
+        Rules::new(rules, default)
+    }
+
+    pub fn users(&self) -> HashMap<String, String> {
+        self.users
+            .iter()
+            .filter_map(|user| {
+                let mut parts = user.splitn(2, ':');
+                Some((parts.next()?.to_string(), parts.next()?.to_string()))
+            })
+            .collect()
+    }
+}
+
+/// Splits `name:password`, both 1 to 255 bytes long.
+fn credentials(user: &str) -> Option<(String, String)> {
+    let i = user.find(':')?;
+    let (name, password) = (&user[..i], &user[i + 1..]);
+    if name.is_empty() || name.len() > 255 || password.is_empty() || password.len() > 255 {
+        return None;
+    }
+    Some((name.to_string(), password.to_string()))
 }
@@ -1,16 +1,16 @@
 use bytes::Buf;
 use openssl::symm::{decrypt_aead, Cipher};
 use std::io;
-use std::io::{Error, ErrorKind, Write};
+use std::io::{Error, ErrorKind};
 use tokio::net::tcp::OwnedReadHalf;
 use tokio::prelude::*;
+use tokio::spawn;
+use tokio::sync::mpsc;
 
 pub struct Decryption {
     cur_key: Vec<u8>,
     alg: Cipher,
     reader: OwnedReadHalf,
-    buffer: Vec<u8>,
-    buffer_offset: usize,
 }
 
 impl Decryption {
@@ -19,8 +19,6 @@ impl Decryption {
             cur_key: key.into_bytes(),
             alg: Cipher::aes_256_gcm(),
             reader,
-            buffer: vec![],
-            buffer_offset: 0,
         }
     }
 
@@ -28,34 +26,25 @@ impl Decryption {
         self.read().await
     }
 
-    pub async fn decryption_read_exact(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        let mut buf_offset: usize = 0;
-        loop {
-            let n = self.fill(buf, buf_offset)?;
-            buf_offset += n;
-
-            if buf_offset >= buf.len() {
-                break;
+    /// Reads frames in a task of their own, so that waiting for the next one
+    /// can be raced against other events without losing a frame half read.
+    /// The channel closes after the first error, which it passes on.
+    pub fn into_frames(mut self) -> mpsc::Receiver<io::Result<Vec<u8>>> {
+        let (mut tx, rx) = mpsc::channel(16);
+        spawn(async move {
+            loop {
+                let frame = self.read().await;
+                let failed = frame.is_err();
+                if tx.send(frame).await.is_err() || failed {
+                    return;
+                }
             }
-
-            self.buffer = self.read().await?;
-            self.buffer_offset = 0;
-        }
-        Ok(buf_offset)
+        });
+        rx
     }
 }
 
 impl Decryption {
-    fn fill(&mut self, buffer: &mut [u8], buf_offset: usize) -> io::Result<usize> {
-        let buffer_range = &self.buffer[self.buffer_offset..];
-        if buffer_range.len() <= 0 {
-            return Ok(0);
-        }
-        let n = (&mut buffer[buf_offset..]).write(buffer_range)?;
-        self.buffer_offset += n;
-        Ok(n)
-    }
-
     fn head_size(&self) -> usize {
         self.alg.iv_len().unwrap_or(0) + 32 + 8
     }
@@ -75,7 +64,7 @@ impl Decryption {
         &head[start..start + 16]
     }
 
-    fn read_body_size<'a>(&self, head: &'a [u8]) -> usize {
+    fn read_body_size(&self, head: &[u8]) -> usize {
         let index = self.alg.iv_len().unwrap() + 32;
         head[index..].as_ref().get_u64() as usize
     }
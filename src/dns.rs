@@ -0,0 +1,422 @@
+use std::collections::HashMap;
+use std::io;
+use std::io::{Error, ErrorKind};
+use std::net::IpAddr;
+use std::time::Instant;
+
+pub const TYPE_A: u16 = 1;
+pub const TYPE_AAAA: u16 = 28;
+const TYPE_SOA: u16 = 6;
+// EDNS pseudo record, whose TTL field holds flags
+const TYPE_OPT: u16 = 41;
+const CLASS_IN: u16 = 1;
+
+pub const RCODE_NO_ERROR: u8 = 0;
+pub const RCODE_FORMAT_ERROR: u8 = 1;
+pub const RCODE_SERVER_FAILURE: u8 = 2;
+pub const RCODE_NAME_ERROR: u8 = 3;
+pub const RCODE_NOT_IMPLEMENTED: u8 = 4;
+
+const HEADER_LEN: usize = 12;
+
+// how long answers without records, e.g. NXDOMAIN, are cached when there is
+// no SOA to tell
+const NEGATIVE_TTL: u32 = 30;
+
+const MAX_CACHE_ENTRIES: usize = 4096;
+
+fn invalid(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, msg.to_string())
+}
+
+fn u16_at(msg: &[u8], i: usize) -> io::Result<u16> {
+    match msg.get(i..i + 2) {
+        None => Err(invalid("message truncated")),
+        Some(b) => Ok(u16::from_be_bytes([b[0], b[1]])),
+    }
+}
+
+/// Skips a possibly compressed name, returning the offset past it.
+fn skip_name(msg: &[u8], mut i: usize) -> io::Result<usize> {
+    loop {
+        let len = *msg.get(i).ok_or_else(|| invalid("name truncated"))? as usize;
+        match len {
+            0 => return Ok(i + 1),
+            // a pointer ends the name
+            _ if len & 0xc0 == 0xc0 => return Ok(i + 2),
+            _ => i += 1 + len,
+        }
+    }
+}
+
+/// The single question of a query.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Question {
+    // lower case, without the trailing dot
+    pub name: String,
+    pub qtype: u16,
+    pub qclass: u16,
+}
+
+impl Question {
+    /// Parses the question of `msg`, returning it along with the offset of
+    /// the first record after it.
+    pub fn parse(msg: &[u8]) -> io::Result<(Question, usize)> {
+        if msg.len() < HEADER_LEN || u16_at(msg, 4)? != 1 {
+            return Err(invalid("expected one question"));
+        }
+        let mut labels = vec![];
+        let mut i = HEADER_LEN;
+        loop {
+            let len = *msg.get(i).ok_or_else(|| invalid("name truncated"))? as usize;
+            i += 1;
+            if len == 0 {
+                break;
+            }
+            if len & 0xc0 != 0 {
+                return Err(invalid("compressed question"));
+            }
+            let label = msg
+                .get(i..i + len)
+                .ok_or_else(|| invalid("name truncated"))?;
+            labels.push(String::from_utf8_lossy(label).to_ascii_lowercase());
+            i += len;
+        }
+        let question = Question {
+            name: labels.join("."),
+            qtype: u16_at(msg, i)?,
+            qclass: u16_at(msg, i + 2)?,
+        };
+        Ok((question, i + 4))
+    }
+
+    /// Whether the name is `domain` or one of its subdomains.
+    pub fn is_under(&self, domain: &str) -> bool {
+        let domain = domain.trim_end_matches('.');
+        self.name.eq_ignore_ascii_case(domain)
+            || self.name.len() > domain.len()
+                && self.name.ends_with(&domain.to_ascii_lowercase())
+                && self.name.as_bytes()[self.name.len() - domain.len() - 1] == b'.'
+    }
+}
+
+pub fn id(msg: &[u8]) -> u16 {
+    u16_at(msg, 0).unwrap_or(0)
+}
+
+pub fn set_id(msg: &mut [u8], id: u16) {
+    if msg.len() >= 2 {
+        msg[..2].copy_from_slice(&id.to_be_bytes());
+    }
+}
+
+pub fn rcode(msg: &[u8]) -> u8 {
+    msg.get(3)
+        .map_or(RCODE_SERVER_FAILURE, |flags| flags & 0x0f)
+}
+
+/// Calls `f` with the index, the type and the offset of the TTL of every
+/// record, answers first. The record data follows the TTL and its length.
+fn for_each_record<F: FnMut(usize, u16, usize)>(msg: &[u8], mut f: F) -> io::Result<()> {
+    let (_, mut i) = Question::parse(msg)?;
+    let count = u16_at(msg, 6)? as usize + u16_at(msg, 8)? as usize + u16_at(msg, 10)? as usize;
+    for index in 0..count {
+        i = skip_name(msg, i)?;
+        let rtype = u16_at(msg, i)?;
+        let rdlength = u16_at(msg, i + 8)? as usize;
+        if msg.len() < i + 10 + rdlength {
+            return Err(invalid("record truncated"));
+        }
+        f(index, rtype, i + 4);
+        i += 10 + rdlength;
+    }
+    Ok(())
+}
+
+/// Largest UDP response the client accepts, as told by its EDNS record.
+pub fn udp_payload_size(query: &[u8]) -> usize {
+    let mut size = 512;
+    let _ = for_each_record(query, |_, rtype, i| {
+        if rtype == TYPE_OPT {
+            // the class field holds the size
+            size = size.max(u16::from_be_bytes([query[i - 2], query[i - 1]]) as usize);
+        }
+    });
+    size
+}
+
+/// Cuts a response too large for UDP down to its question, with the TC flag
+/// set so that the client retries over TCP.
+pub fn truncate(msg: &mut Vec<u8>, max: usize) {
+    if msg.len() <= max {
+        return;
+    }
+    let end = Question::parse(msg).map_or(HEADER_LEN, |(_, end)| end);
+    msg.truncate(end);
+    msg[2] |= 0x02;
+    msg[6..12].copy_from_slice(&[0; 6]);
+}
+
+/// How long a response may be cached: its smallest TTL, or for answers
+/// without records the SOA's, capped by its MINIMUM as RFC 2308 has it.
+/// `None` when it should not be cached at all.
+pub fn cache_ttl(msg: &[u8]) -> Option<u32> {
+    if rcode(msg) != RCODE_NO_ERROR && rcode(msg) != RCODE_NAME_ERROR {
+        return None;
+    }
+    let answers = u16_at(msg, 6).ok()? as usize;
+    let mut ttl: Option<u32> = None;
+    for_each_record(msg, |index, rtype, i| {
+        if index < answers || answers == 0 && rtype == TYPE_SOA {
+            let mut record_ttl = u32::from_be_bytes([msg[i], msg[i + 1], msg[i + 2], msg[i + 3]]);
+            // MINIMUM ends the SOA data, after two names and four numbers
+            match u16_at(msg, i + 4) {
+                Ok(rdlength) if index >= answers && rdlength >= 2 + 20 => {
+                    let m = i + 2 + rdlength as usize;
+                    let minimum = u32::from_be_bytes([msg[m], msg[m + 1], msg[m + 2], msg[m + 3]]);
+                    record_ttl = record_ttl.min(minimum);
+                }
+                _ => {}
+            }
+            ttl = Some(ttl.map_or(record_ttl, |ttl| ttl.min(record_ttl)));
+        }
+    })
+    .ok()?;
+    Some(ttl.unwrap_or(NEGATIVE_TTL))
+}
+
+/// Lowers every record TTL by `elapsed` seconds.
+fn age(msg: &mut [u8], elapsed: u32) {
+    let mut offsets = vec![];
+    let found = for_each_record(msg, |_, rtype, i| {
+        if rtype != TYPE_OPT {
+            offsets.push(i);
+        }
+    });
+    if found.is_err() {
+        return;
+    }
+    for i in offsets {
+        let ttl = u32::from_be_bytes([msg[i], msg[i + 1], msg[i + 2], msg[i + 3]]);
+        msg[i..i + 4].copy_from_slice(&ttl.saturating_sub(elapsed).to_be_bytes());
+    }
+}
+
+/// Builds a recursive query for `name`, advertising a UDP payload size of
+/// `udp_size` in an EDNS record.
+pub fn query(id: u16, name: &str, qtype: u16, udp_size: u16) -> io::Result<Vec<u8>> {
+    let mut msg = id.to_be_bytes().to_vec();
+    // RD, one question, one additional record
+    msg.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 1]);
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("invalid name {:?}", name),
+            ));
+        }
+        msg.push(label.len() as u8);
+        msg.extend_from_slice(label.as_bytes());
+    }
+    msg.push(0);
+    msg.extend_from_slice(&qtype.to_be_bytes());
+    msg.extend_from_slice(&CLASS_IN.to_be_bytes());
+    // root name, OPT, payload size, no extended flags, no data
+    msg.push(0);
+    msg.extend_from_slice(&TYPE_OPT.to_be_bytes());
+    msg.extend_from_slice(&udp_size.to_be_bytes());
+    msg.extend_from_slice(&[0, 0, 0, 0, 0, 0]);
+    Ok(msg)
+}
+
+/// Addresses in the answers of a response, whichever names they belong to,
+/// which for a recursive resolver's answer are the query's after CNAMEs.
+pub fn addresses(msg: &[u8]) -> io::Result<Vec<IpAddr>> {
+    let answers = u16_at(msg, 6)? as usize;
+    let mut addrs = vec![];
+    for_each_record(msg, |index, rtype, i| {
+        let rdata = &msg[i + 6..];
+        if index >= answers {
+            return;
+        }
+        match (rtype, u16_at(msg, i + 4)) {
+            (TYPE_A, Ok(4)) => {
+                addrs.push(IpAddr::from([rdata[0], rdata[1], rdata[2], rdata[3]]));
+            }
+            (TYPE_AAAA, Ok(16)) => {
+                let mut octets = [0_u8; 16];
+                octets.copy_from_slice(&rdata[..16]);
+                addrs.push(IpAddr::from(octets));
+            }
+            _ => {}
+        }
+    })?;
+    Ok(addrs)
+}
+
+/// Builds a response to `query` carrying `rcode` and no records.
+pub fn error_response(query: &[u8], rcode: u8) -> Vec<u8> {
+    let end = Question::parse(query).map_or(HEADER_LEN, |(_, end)| end);
+    let mut msg = query[..end.min(query.len())].to_vec();
+    msg.resize(HEADER_LEN.max(msg.len()), 0);
+    // QR, keep opcode and RD, RA
+    msg[2] = 0x80 | (msg[2] & 0x79);
+    msg[3] = 0x80 | rcode;
+    if end == HEADER_LEN {
+        msg[4..6].copy_from_slice(&[0, 0]);
+    }
+    msg[6..12].copy_from_slice(&[0; 6]);
+    msg
+}
+
+/// Builds a response to `query` answering its question with `addrs`, only
+/// those matching the question type.
+pub fn address_response(query: &[u8], addrs: &[IpAddr], ttl: u32) -> Vec<u8> {
+    let (question, _) = match Question::parse(query) {
+        Err(_) => return error_response(query, RCODE_SERVER_FAILURE),
+        Ok(question) => question,
+    };
+    let mut msg = error_response(query, RCODE_NO_ERROR);
+    let mut count: u16 = 0;
+    for addr in addrs {
+        let (rtype, rdata) = match addr {
+            IpAddr::V4(ip) => (TYPE_A, ip.octets().to_vec()),
+            IpAddr::V6(ip) => (TYPE_AAAA, ip.octets().to_vec()),
+        };
+        if rtype != question.qtype || question.qclass != CLASS_IN {
+            continue;
+        }
+        // the name is a pointer to the question
+        msg.extend_from_slice(&[0xc0, HEADER_LEN as u8]);
+        msg.extend_from_slice(&rtype.to_be_bytes());
+        msg.extend_from_slice(&CLASS_IN.to_be_bytes());
+        msg.extend_from_slice(&ttl.to_be_bytes());
+        msg.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        msg.extend_from_slice(&rdata);
+        count += 1;
+    }
+    msg[6..8].copy_from_slice(&count.to_be_bytes());
+    msg
+}
+
+struct Entry {
+    response: Vec<u8>,
+    stored: Instant,
+    ttl: u32,
+}
+
+/// Responses by question, kept for as long as their TTL allows.
+#[derive(Default)]
+pub struct Cache {
+    entries: HashMap<Question, Entry>,
+}
+
+impl Cache {
+    /// Returns a cached response with the given ID and TTLs aged.
+    pub fn get(&mut self, question: &Question, id: u16) -> Option<Vec<u8>> {
+        let entry = self.entries.get(question)?;
+        let elapsed = entry.stored.elapsed().as_secs();
+        if elapsed >= entry.ttl as u64 {
+            self.entries.remove(question);
+            return None;
+        }
+        let mut response = entry.response.clone();
+        set_id(&mut response, id);
+        age(&mut response, elapsed as u32);
+        Some(response)
+    }
+
+    /// Whether a cached answer, expired or not, gave `ip` as an address of
+    /// `name`.
+    pub fn answered(&self, name: &str, ip: &IpAddr) -> bool {
+        let question = Question {
+            name: name.trim_end_matches('.').to_ascii_lowercase(),
+            qtype: if ip.is_ipv4() { TYPE_A } else { TYPE_AAAA },
+            qclass: CLASS_IN,
+        };
+        match self.entries.get(&question) {
+            None => false,
+            Some(entry) => match addresses(&entry.response) {
+                Err(_) => false,
+                Ok(addrs) => addrs.contains(ip),
+            },
+        }
+    }
+
+    pub fn put(&mut self, question: Question, response: &[u8]) {
+        let ttl = match cache_ttl(response) {
+            None | Some(0) => return,
+            Some(ttl) => ttl,
+        };
+        if self.entries.len() >= MAX_CACHE_ENTRIES {
+            self.entries
+                .retain(|_, entry| entry.stored.elapsed().as_secs() < entry.ttl as u64);
+            if self.entries.len() >= MAX_CACHE_ENTRIES {
+                self.entries.clear();
+            }
+        }
+        let entry = Entry {
+            response: response.to_vec(),
+            stored: Instant::now(),
+            ttl,
+        };
+        self.entries.insert(question, entry);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A response to `query` without answers, with an SOA record in the
+    /// authority section.
+    fn negative(query: &[u8], rcode: u8, soa_ttl: u32, minimum: u32) -> Vec<u8> {
+        let mut msg = error_response(query, rcode);
+        msg[8..10].copy_from_slice(&1_u16.to_be_bytes());
+        msg.extend_from_slice(&[0xc0, HEADER_LEN as u8]);
+        msg.extend_from_slice(&TYPE_SOA.to_be_bytes());
+        msg.extend_from_slice(&CLASS_IN.to_be_bytes());
+        msg.extend_from_slice(&soa_ttl.to_be_bytes());
+        // root MNAME and RNAME, SERIAL, REFRESH, RETRY, EXPIRE, MINIMUM
+        msg.extend_from_slice(&22_u16.to_be_bytes());
+        msg.extend_from_slice(&[0, 0]);
+        for value in &[1, 7200, 3600, 1_209_600, minimum] {
+            msg.extend_from_slice(&value.to_be_bytes());
+        }
+        msg
+    }
+
+    #[test]
+    fn negative_ttl() {
+        let query = query(1, "example.com", TYPE_AAAA, 1232).unwrap();
+        let nodata = negative(&query, RCODE_NO_ERROR, 86400, 300);
+        assert!(addresses(&nodata).unwrap().is_empty());
+        assert_eq!(cache_ttl(&nodata), Some(300));
+        assert_eq!(
+            cache_ttl(&negative(&query, RCODE_NO_ERROR, 60, 300)),
+            Some(60)
+        );
+        assert_eq!(
+            cache_ttl(&negative(&query, RCODE_NAME_ERROR, 86400, 900)),
+            Some(900)
+        );
+        // without an SOA
+        assert_eq!(
+            cache_ttl(&error_response(&query, RCODE_NAME_ERROR)),
+            Some(NEGATIVE_TTL)
+        );
+        assert_eq!(
+            cache_ttl(&error_response(&query, RCODE_SERVER_FAILURE)),
+            None
+        );
+    }
+
+    #[test]
+    fn positive_ttl() {
+        let query = query(1, "example.com", TYPE_A, 1232).unwrap();
+        let ip = "192.0.2.1".parse().unwrap();
+        let response = address_response(&query, &[ip], 120);
+        assert_eq!(cache_ttl(&response), Some(120));
+        assert_eq!(addresses(&response).unwrap(), vec![ip]);
+    }
+}
@@ -40,7 +40,7 @@ impl Encryption {
 
         let mut tag = [0_u8; 16];
 
-        let ct = encrypt_aead(self.alg, &self.cur_key, Some(&iv), &aad, &data, &mut tag).unwrap();
+        let ct = encrypt_aead(self.alg, &self.cur_key, Some(&iv), &aad, data, &mut tag).unwrap();
 
         let mut buffer = vec![];
         Write::write(&mut buffer, &iv).unwrap();
@@ -53,6 +53,6 @@ impl Encryption {
 
     pub async fn encryption_write(&mut self, buf: &[u8]) -> io::Result<()> {
         let data = self.en(buf);
-        io::Result::Ok(self.writer.write_all(&data).await?)
+        self.writer.write_all(&data).await
     }
 }
@@ -0,0 +1,575 @@
+use crate::address::Address;
+use crate::request::Request;
+use crate::socks5;
+use openssl::base64;
+use std::collections::HashMap;
+use std::io;
+use std::io::{Error, ErrorKind};
+use std::net::SocketAddr;
+use tokio::net::TcpStream;
+use tokio::prelude::*;
+
+// longest request or response head accepted
+const MAX_HEAD_LEN: usize = 16 * 1024;
+
+// longest chunk size or trailer line accepted
+const MAX_LINE_LEN: usize = 4096;
+
+pub const PROXY_AUTH_REQUIRED: &[u8] = b"HTTP/1.1 407 Proxy Authentication Required\r\n\
+Proxy-Authenticate: Basic realm=\"proxy-rs\"\r\nContent-Length: 0\r\n\r\n";
+
+pub const BAD_REQUEST: &[u8] =
+    b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+
+pub const CONTINUE: &[u8] = b"HTTP/1.1 100 Continue\r\n\r\n";
+
+// headers meant for a single connection (RFC 7230 section 6.1), never
+// passed on to the origin server
+const HOP_BY_HOP: [&str; 7] = [
+    "Connection",
+    "Keep-Alive",
+    "Proxy-Authorization",
+    "Proxy-Connection",
+    "TE",
+    "Trailer",
+    "Upgrade",
+];
+
+/// Parsed request line and headers of an HTTP/1.x request.
+pub struct Head {
+    pub method: String,
+    pub uri: String,
+    pub version: String,
+    pub headers: Vec<(String, String)>,
+}
+
+/// Parsed status line and headers of an HTTP/1.x response.
+pub struct ResponseHead {
+    pub version: String,
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+}
+
+fn header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.as_str())
+}
+
+/// Whether a comma separated header value lists `token`.
+fn has_token(value: Option<&str>, token: &str) -> bool {
+    match value {
+        None => false,
+        Some(value) => value
+            .split(',')
+            .any(|t| t.trim().eq_ignore_ascii_case(token)),
+    }
+}
+
+fn keep_alive(version: &str, connection: Option<&str>) -> bool {
+    if version == "HTTP/1.0" {
+        has_token(connection, "keep-alive")
+    } else {
+        !has_token(connection, "close")
+    }
+}
+
+impl Head {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        header(&self.headers, name)
+    }
+
+    /// Whether the client keeps the connection open after this request.
+    pub fn keep_alive(&self) -> bool {
+        let connection = self
+            .header("Proxy-Connection")
+            .or_else(|| self.header("Connection"));
+        keep_alive(&self.version, connection)
+    }
+
+    pub fn expects_continue(&self) -> bool {
+        has_token(self.header("Expect"), "100-continue")
+    }
+
+    /// Splits an absolute `http://` URI into the target and the path in
+    /// origin form.
+    pub fn absolute_target(&self) -> Option<(Address, String)> {
+        let scheme = self.uri.get(..7)?;
+        if !scheme.eq_ignore_ascii_case("http://") {
+            return None;
+        }
+        let rest = &self.uri[7..];
+        let end = rest.find(&['/', '?'][..]).unwrap_or(rest.len());
+        let authority = &rest[..end];
+        let authority = &authority[authority.rfind('@').map_or(0, |i| i + 1)..];
+        let target = parse_authority(authority, 80)?;
+        let path = match &rest[end..] {
+            "" => "/".to_string(),
+            path if path.starts_with('?') => format!("/{}", path),
+            path => path.to_string(),
+        };
+        Some((target, path))
+    }
+
+    /// Rewrites the head for the origin server: origin form request line,
+    /// hop-by-hop headers and `Expect` removed, `Host` added if missing.
+    pub fn to_origin_form(&self, target: &Address, path: &str) -> Vec<u8> {
+        let listed: Vec<&str> = self
+            .header("Connection")
+            .map(|value| value.split(',').map(|t| t.trim()).collect())
+            .unwrap_or_default();
+        let mut head = format!("{} {} {}\r\n", self.method, path, self.version);
+        if self.header("Host").is_none() {
+            match target {
+                Address::Domain(host, 80) => head.push_str(&format!("Host: {}\r\n", host)),
+                _ => head.push_str(&format!("Host: {}\r\n", target)),
+            }
+        }
+        for (name, value) in &self.headers {
+            let skip = HOP_BY_HOP.iter().any(|h| h.eq_ignore_ascii_case(name))
+                || listed.iter().any(|h| h.eq_ignore_ascii_case(name))
+                || name.eq_ignore_ascii_case("Expect");
+            if !skip {
+                head.push_str(&format!("{}: {}\r\n", name, value));
+            }
+        }
+        head.push_str("\r\n");
+        head.into_bytes()
+    }
+}
+
+impl ResponseHead {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        header(&self.headers, name)
+    }
+
+    /// Whether the origin server keeps the connection open after this
+    /// response.
+    pub fn keep_alive(&self) -> bool {
+        keep_alive(&self.version, self.header("Connection"))
+    }
+}
+
+/// Returns the length of the head at the front of `buf`, blank line
+/// included, once it is complete.
+pub fn head_len(buf: &[u8]) -> io::Result<Option<usize>> {
+    match buf.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(i) => Ok(Some(i + 4)),
+        None if buf.len() > MAX_HEAD_LEN => {
+            Err(Error::new(ErrorKind::InvalidData, "head too long"))
+        }
+        None => Ok(None),
+    }
+}
+
+type Headers = Vec<(String, String)>;
+
+/// Splits a head into the words of its first line and its headers.
+fn parse_lines(buf: &[u8]) -> io::Result<(Vec<&str>, Headers)> {
+    let text = match std::str::from_utf8(buf) {
+        Err(err) => return Err(Error::new(ErrorKind::InvalidData, err)),
+        Ok(text) => text,
+    };
+    let mut lines = text.split("\r\n");
+    let first_line = lines.next().unwrap_or("").splitn(3, ' ').collect();
+    let mut headers = vec![];
+    for line in lines.filter(|line| !line.is_empty()) {
+        let mut parts = line.splitn(2, ':');
+        let name = parts.next().unwrap_or("").trim();
+        let value = match parts.next() {
+            None => return Err(Error::new(ErrorKind::InvalidData, "malformed header")),
+            Some(value) => value,
+        };
+        headers.push((name.to_string(), value.trim().to_string()));
+    }
+    Ok((first_line, headers))
+}
+
+pub fn parse_request(buf: &[u8]) -> io::Result<Head> {
+    let (request_line, headers) = parse_lines(buf)?;
+    match request_line[..] {
+        [method, uri, version] if version.starts_with("HTTP/1.") => Ok(Head {
+            method: method.to_string(),
+            uri: uri.to_string(),
+            version: version.to_string(),
+            headers,
+        }),
+        _ => Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("invalid request line {:?}", request_line),
+        )),
+    }
+}
+
+pub fn parse_response(buf: &[u8]) -> io::Result<ResponseHead> {
+    let (status_line, headers) = parse_lines(buf)?;
+    let status = status_line.get(1).and_then(|status| status.parse().ok());
+    match (status_line.first(), status) {
+        (Some(version), Some(status)) if version.starts_with("HTTP/1.") => Ok(ResponseHead {
+            version: version.to_string(),
+            status,
+            headers,
+        }),
+        _ => Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("invalid status line {:?}", status_line),
+        )),
+    }
+}
+
+/// Reads an HTTP request head, `buffer` holding what was already read from
+/// the client. Bytes past the head are left in `buffer`.
+pub async fn read_head<R: AsyncRead + Unpin>(s: &mut R, buffer: &mut Vec<u8>) -> io::Result<Head> {
+    let mut chunk = [0_u8; 2048];
+    let len = loop {
+        if let Some(len) = head_len(buffer)? {
+            break len;
+        }
+        let n = s.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "request head truncated",
+            ));
+        }
+        buffer.extend_from_slice(&chunk[..n]);
+    };
+    let head = parse_request(&buffer[..len])?;
+    buffer.drain(..len);
+    Ok(head)
+}
+
+/// Parses `host:port`, with IPv6 hosts in brackets, falling back to
+/// `default_port` when the port is missing.
+pub fn parse_authority(authority: &str, default_port: u16) -> Option<Address> {
+    if let Ok(addr) = authority.parse::<SocketAddr>() {
+        return Some(Address::Ip(addr));
+    }
+    let (host, port) = match authority.rfind(':') {
+        Some(i) if !authority[i..].contains(']') => {
+            (&authority[..i], authority[i + 1..].parse().ok()?)
+        }
+        _ => (authority, default_port),
+    };
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if let Ok(ip) = host.parse() {
+        return Some(Address::Ip(SocketAddr::new(ip, port)));
+    }
+    if host.is_empty() || host.len() > 255 {
+        return None;
+    }
+    Some(Address::Domain(host.to_string(), port))
+}
+
+/// Checks `Proxy-Authorization: Basic` credentials and returns the user.
+/// Always succeeds with no user when `users` is empty.
+pub fn authenticate(head: &Head, users: &HashMap<String, String>) -> Result<Option<String>, ()> {
+    if users.is_empty() {
+        return Ok(None);
+    }
+    let credentials = head
+        .header("Proxy-Authorization")
+        .and_then(|value| {
+            let mut parts = value.splitn(2, ' ');
+            match parts.next() {
+                Some(scheme) if scheme.eq_ignore_ascii_case("Basic") => parts.next(),
+                _ => None,
+            }
+        })
+        .and_then(|token| base64::decode_block(token.trim()).ok())
+        .and_then(|decoded| String::from_utf8(decoded).ok())
+        .ok_or(())?;
+    let mut parts = credentials.splitn(2, ':');
+    let name = parts.next().unwrap_or("");
+    match (users.get(name), parts.next()) {
+        (Some(expected), Some(password))
+            if socks5::password_eq(expected.as_bytes(), password.as_bytes()) =>
+        {
+            Ok(Some(name.to_string()))
+        }
+        _ => Err(()),
+    }
+}
+
+/// Checks an HTTP `CONNECT` request and returns the target. The reply is
+/// left to the caller.
+///
+/// When `users` is not empty, clients must send Basic credentials for one of
+/// its name/password pairs.
+pub async fn handshake(
+    s: &mut TcpStream,
+    head: &Head,
+    users: &HashMap<String, String>,
+) -> io::Result<Request> {
+    let user = match authenticate(head, users) {
+        Err(_) => {
+            s.write_all(PROXY_AUTH_REQUIRED).await?;
+            return Err(Error::new(
+                ErrorKind::PermissionDenied,
+                "authentication failed",
+            ));
+        }
+        Ok(user) => user,
+    };
+    let target = match parse_authority(&head.uri, 443) {
+        None => {
+            s.write_all(BAD_REQUEST).await?;
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("invalid authority {:?}", head.uri),
+            ));
+        }
+        Some(target) => target,
+    };
+    Ok(Request::new(socks5::CMD_CONNECT, user, target))
+}
+
+/// Answers a `CONNECT` request, or a request that could not be forwarded,
+/// mapping the SOCKS5 reply code `rep` onto an HTTP status.
+pub async fn reply<W: AsyncWrite + Unpin>(s: &mut W, rep: u8) -> io::Result<()> {
+    let status = match rep {
+        socks5::REP_SUCCEEDED => "200 Connection established",
+        socks5::REP_CONNECTION_NOT_ALLOWED => "403 Forbidden",
+        socks5::REP_TTL_EXPIRED => "504 Gateway Timeout",
+        _ => "502 Bad Gateway",
+    };
+    let mut resp = format!("HTTP/1.1 {}\r\n", status);
+    if rep != socks5::REP_SUCCEEDED {
+        resp.push_str("Content-Length: 0\r\nConnection: close\r\n");
+    }
+    resp.push_str("\r\n");
+    s.write_all(resp.as_bytes()).await
+}
+
+/// Position inside a chunked body.
+pub enum Chunked {
+    Size(Vec<u8>),
+    Data(u64),
+    // CRLF closing a chunk
+    DataEnd(usize),
+    Trailer(Vec<u8>),
+}
+
+/// Finds where a message body ends as it streams through.
+pub enum Body {
+    Empty,
+    Length(u64),
+    Chunked(Chunked),
+    UntilEof,
+}
+
+impl Body {
+    /// Fails on a `Content-Length` that is not a number, or that differs
+    /// between repeats, as the two ends could then disagree on where the
+    /// message ends.
+    fn from_headers(headers: &[(String, String)]) -> io::Result<Option<Body>> {
+        if has_token(header(headers, "Transfer-Encoding"), "chunked") {
+            return Ok(Some(Body::Chunked(Chunked::Size(vec![]))));
+        }
+        let mut len = None;
+        let values = headers
+            .iter()
+            .filter(|(key, _)| key.eq_ignore_ascii_case("Content-Length"))
+            .flat_map(|(_, value)| value.split(','));
+        for value in values {
+            let value = value.trim();
+            // digits only, where parse would also take a sign
+            let parsed = Some(value)
+                .filter(|value| value.bytes().all(|b| b.is_ascii_digit()))
+                .and_then(|value| value.parse::<u64>().ok());
+            match (parsed, len) {
+                (None, _) => {
+                    return Err(Error::new(ErrorKind::InvalidData, "invalid content length"))
+                }
+                (Some(parsed), Some(len)) if parsed != len => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        "conflicting content lengths",
+                    ))
+                }
+                (parsed, _) => len = parsed,
+            }
+        }
+        Ok(len.map(Body::Length))
+    }
+
+    pub fn request(head: &Head) -> io::Result<Body> {
+        Ok(Body::from_headers(&head.headers)?.unwrap_or(Body::Empty))
+    }
+
+    pub fn response(head: &ResponseHead, method: &str) -> io::Result<Body> {
+        if method.eq_ignore_ascii_case("HEAD")
+            || head.status / 100 == 1
+            || head.status == 204
+            || head.status == 304
+        {
+            return Ok(Body::Empty);
+        }
+        Ok(Body::from_headers(&head.headers)?.unwrap_or(Body::UntilEof))
+    }
+
+    /// Returns how many bytes at the front of `buf` belong to the body, and
+    /// whether the body ends there.
+    pub fn feed(&mut self, buf: &[u8]) -> io::Result<(usize, bool)> {
+        match self {
+            Body::Empty => Ok((0, true)),
+            Body::UntilEof => Ok((buf.len(), false)),
+            Body::Length(remaining) => {
+                let n = (*remaining).min(buf.len() as u64);
+                *remaining -= n;
+                Ok((n as usize, *remaining == 0))
+            }
+            Body::Chunked(state) => feed_chunked(state, buf),
+        }
+    }
+}
+
+fn feed_chunked(state: &mut Chunked, buf: &[u8]) -> io::Result<(usize, bool)> {
+    let mut i = 0;
+    while i < buf.len() {
+        let line = match state {
+            Chunked::Data(remaining) => {
+                let n = (*remaining).min((buf.len() - i) as u64);
+                *remaining -= n;
+                i += n as usize;
+                if *remaining == 0 {
+                    *state = Chunked::DataEnd(2);
+                }
+                continue;
+            }
+            Chunked::DataEnd(remaining) => {
+                let n = (*remaining).min(buf.len() - i);
+                *remaining -= n;
+                i += n;
+                if *remaining == 0 {
+                    *state = Chunked::Size(vec![]);
+                }
+                continue;
+            }
+            Chunked::Size(line) | Chunked::Trailer(line) => line,
+        };
+        let end = buf[i..].iter().position(|b| *b == b'\n');
+        let n = end.map_or(buf.len() - i, |end| end + 1);
+        line.extend_from_slice(&buf[i..i + n]);
+        i += n;
+        if line.len() > MAX_LINE_LEN {
+            return Err(Error::new(ErrorKind::InvalidData, "chunk line too long"));
+        }
+        if end.is_none() {
+            break;
+        }
+
+        *state = match state {
+            Chunked::Size(line) => {
+                let line = String::from_utf8_lossy(line);
+                let size = line.split(';').next().unwrap_or("").trim();
+                match u64::from_str_radix(size, 16) {
+                    Err(_) => return Err(Error::new(ErrorKind::InvalidData, "invalid chunk size")),
+                    Ok(0) => Chunked::Trailer(vec![]),
+                    Ok(size) => Chunked::Data(size),
+                }
+            }
+            // a blank line ends the trailer, and the body
+            Chunked::Trailer(line) if line.len() <= 2 => return Ok((i, true)),
+            _ => Chunked::Trailer(vec![]),
+        };
+    }
+    Ok((i, false))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(head: &str, method: &str) -> Body {
+        Body::response(&parse_response(head.as_bytes()).unwrap(), method).unwrap()
+    }
+
+    /// Feeds `buf` in pieces of `step` bytes, returning how many belong to
+    /// the body, if it ended.
+    fn body_len(body: &mut Body, buf: &[u8], step: usize) -> Option<usize> {
+        let mut fed = 0;
+        for piece in buf.chunks(step) {
+            let (n, done) = body.feed(piece).unwrap();
+            fed += n;
+            if done {
+                return Some(fed);
+            }
+            assert_eq!(n, piece.len());
+        }
+        None
+    }
+
+    #[test]
+    fn body_length() {
+        let head = parse_request(b"POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\n").unwrap();
+        let mut body = Body::request(&head).unwrap();
+        assert_eq!(body.feed(b"abc").unwrap(), (3, false));
+        assert_eq!(body.feed(b"deGET").unwrap(), (2, true));
+        let head = parse_request(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        assert!(matches!(Body::request(&head), Ok(Body::Empty)));
+    }
+
+    #[test]
+    fn body_invalid_length() {
+        for len in &[
+            "abc",
+            "-1",
+            "+5",
+            "",
+            "5 5",
+            "1, 2",
+            "0x10",
+            "99999999999999999999",
+        ] {
+            let head = format!("POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n", len);
+            let head = parse_request(head.as_bytes()).unwrap();
+            assert!(Body::request(&head).is_err(), "{:?}", len);
+        }
+        let head = parse_response(b"HTTP/1.1 200 OK\r\nContent-Length: x\r\n\r\n").unwrap();
+        assert!(Body::response(&head, "GET").is_err());
+    }
+
+    #[test]
+    fn body_repeated_length() {
+        let head = "POST / HTTP/1.1\r\nContent-Length: 5\r\nContent-Length: 6\r\n\r\n";
+        assert!(Body::request(&parse_request(head.as_bytes()).unwrap()).is_err());
+        // the same length repeated is one length
+        let head = "POST / HTTP/1.1\r\nContent-Length: 5, 5\r\ncontent-length: 5\r\n\r\n";
+        let body = Body::request(&parse_request(head.as_bytes()).unwrap());
+        assert!(matches!(body, Ok(Body::Length(5))));
+    }
+
+    #[test]
+    fn body_chunked() {
+        let chunked = b"5;ext=1\r\nhello\r\n10\r\n0123456789abcdef\r\n0\r\nTrailer: x\r\n\r\nnext";
+        let len = chunked.len() - 4;
+        for step in 1..=chunked.len() {
+            let mut body = response(
+                "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n",
+                "GET",
+            );
+            assert_eq!(body_len(&mut body, chunked, step), Some(len), "{}", step);
+        }
+        let mut body = response(
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n",
+            "GET",
+        );
+        assert!(body.feed(b"zz\r\n").is_err());
+    }
+
+    #[test]
+    fn body_response() {
+        let head = "HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\n";
+        assert!(matches!(response(head, "HEAD"), Body::Empty));
+        assert!(matches!(response(head, "GET"), Body::Length(10)));
+        for status in &["204 No Content", "304 Not Modified", "100 Continue"] {
+            let head = format!("HTTP/1.1 {}\r\nContent-Length: 10\r\n\r\n", status);
+            assert!(matches!(response(&head, "GET"), Body::Empty), "{}", status);
+        }
+        let mut body = response("HTTP/1.0 200 OK\r\n\r\n", "GET");
+        assert!(matches!(body, Body::UntilEof));
+        assert_eq!(body.feed(b"anything").unwrap(), (8, false));
+    }
+}
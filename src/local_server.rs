@@ -1,18 +1,193 @@
+use crate::acl;
+use crate::address::Address;
 use crate::config::Config;
 use crate::decryption::Decryption;
+use crate::dns;
 use crate::encryption::Encryption;
+use crate::http;
+use crate::outbound::Outbound;
+use crate::redir;
+use crate::request::{Reply, Request, CMD_RESOLVE, CMD_REVERSE, CMD_REVERSE_ACCEPT};
+use crate::rules::{Action, Rules};
+use crate::sniff;
+use crate::sniff::Sniff;
+use crate::socks4;
+use crate::socks5;
+use crate::tproxy;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
 use std::error::Error;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use tokio::net::tcp::OwnedReadHalf;
 use tokio::net::tcp::OwnedWriteHalf;
-use tokio::net::{TcpListener, TcpStream};
+use tokio::net::{lookup_host, TcpListener, TcpStream, UdpSocket};
 use tokio::prelude::*;
 use tokio::runtime::Runtime;
-use tokio::spawn;
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::time::{delay_for, interval, timeout};
+use tokio::{select, spawn};
 
-pub struct LocalServer {
-    listen: String,
+// How long to wait by default, in fast open mode, for the client's first
+// payload after the SOCKS5 reply, so that it can be sent to the remote
+// together with the target address.
+const EARLY_DATA_TIMEOUT: Duration = Duration::from_millis(20);
+
+// How long to wait, in transparent modes, for the client's first bytes to
+// carry a host name.
+const SNIFF_TIMEOUT: Duration = Duration::from_millis(200);
+
+// How often a reverse tunnel control connection sends a keepalive, and how
+// long it may go without hearing back.
+const REVERSE_KEEPALIVE: Duration = Duration::from_secs(30);
+const REVERSE_IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+
+// How long to wait before reopening a failed reverse tunnel.
+const REVERSE_RETRY: Duration = Duration::from_secs(5);
+
+// How long a forwarded DNS query may take.
+const DNS_TIMEOUT: Duration = Duration::from_secs(5);
+
+// TPROXY UDP sessions without outgoing datagrams for this long are closed
+const UDP_IDLE_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Protocol spoken by a local client.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Inbound {
+    Socks4,
+    Socks5,
+    Http,
+    Redir,
+    Tproxy,
+    Tunnel,
+    Dns,
+}
+
+impl Inbound {
+    /// Answers a request in the client's protocol, `rep` being a SOCKS5
+    /// reply code.
+    async fn reply<W: AsyncWrite + Unpin>(
+        self,
+        w: &mut W,
+        rep: u8,
+        bind: &Address,
+    ) -> io::Result<()> {
+        match self {
+            Inbound::Socks4 => socks4::reply(w, rep, bind).await,
+            Inbound::Socks5 => socks5::reply(w, rep, bind).await,
+            Inbound::Http => http::reply(w, rep).await,
+            // transparent clients think they are talking to the target
+            // not proxy clients
+            Inbound::Redir | Inbound::Tproxy | Inbound::Tunnel | Inbound::Dns => Ok(()),
+        }
+    }
+
+    /// Name of the listener kind, as matched by `inbound` rules.
+    fn name(self) -> &'static str {
+        match self {
+            Inbound::Socks4 | Inbound::Socks5 => "socks",
+            Inbound::Http => "http",
+            Inbound::Redir => "redir",
+            Inbound::Tproxy => "tproxy",
+            Inbound::Tunnel => "tunnel",
+            Inbound::Dns => "dns",
+        }
+    }
+}
+
+/// Settings shared by every client connection.
+struct Context {
     remote_addr: String,
     key: String,
+    users: HashMap<String, String>,
+    fast_open: bool,
+    sniff_ports: Vec<(u16, u16)>,
+    // send sniffed names the DNS listener has not answered with the target
+    sniff_override: bool,
+    // 0 sends the request without waiting for early data
+    early_data_timeout: Duration,
+    // name and password reverse tunnels are opened as
+    reverse_user: Option<(String, String)>,
+    // resolver reached through the tunnel over TCP, else the remote resolves
+    dns_upstream: Option<Address>,
+    // domains resolved directly by `dns_direct_upstream`
+    dns_direct: Vec<String>,
+    dns_direct_upstream: Option<SocketAddr>,
+    dns_cache: Mutex<dns::Cache>,
+    rules: Rules,
+    // how connections routed by `direct` rules leave
+    direct: Outbound,
+}
+
+/// Connection to the origin server of the last plain HTTP request, kept for
+/// the next request to the same target.
+struct Upstream {
+    target: Address,
+    link: Link,
+    // response bytes not yet forwarded to the client
+    buffer: Vec<u8>,
+}
+
+/// How an `Upstream` reaches the origin server.
+enum Link {
+    Tunnel(Encryption, Decryption),
+    Direct(TcpStream),
+}
+
+impl Upstream {
+    async fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        match &mut self.link {
+            Link::Tunnel(en, _) => en.encryption_write(data).await,
+            Link::Direct(s1) => s1.write_all(data).await,
+        }
+    }
+
+    /// Reads more of the response into `buffer`.
+    async fn read(&mut self) -> io::Result<()> {
+        match &mut self.link {
+            Link::Tunnel(_, de) => {
+                let data = de.decryption_read().await?;
+                self.buffer.extend_from_slice(&data);
+            }
+            Link::Direct(s1) => {
+                let mut chunk = [0_u8; 2048];
+                let n = s1.read(&mut chunk).await?;
+                if n == 0 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "origin server closed",
+                    ));
+                }
+                self.buffer.extend_from_slice(&chunk[..n]);
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads a response head, returning it together with its length in
+    /// `buffer`.
+    async fn read_response_head(&mut self) -> io::Result<(usize, http::ResponseHead)> {
+        loop {
+            if let Some(len) = http::head_len(&self.buffer)? {
+                return Ok((len, http::parse_response(&self.buffer[..len])?));
+            }
+            self.read().await?;
+        }
+    }
+}
+
+pub struct LocalServer {
+    listen: String,
+    http_listen: Option<String>,
+    redir_listen: Option<String>,
+    tproxy_listen: Option<SocketAddr>,
+    tunnels: Vec<(String, Address)>,
+    reverses: Vec<(SocketAddr, Address)>,
+    dns_listen: Option<String>,
+    context: Arc<Context>,
 }
 
 impl LocalServer {
@@ -21,8 +196,39 @@ impl LocalServer {
 
         Ok(LocalServer {
             listen: config.listen.to_string(),
-            remote_addr: config.remote_addr.to_string(),
-            key: config.key.to_string(),
+            http_listen: config.http_listen.map(|listen| listen.to_string()),
+            redir_listen: config.redir_listen.map(|listen| listen.to_string()),
+            tproxy_listen: match config.tproxy_listen {
+                None => None,
+                Some(listen) => Some(listen.parse()?),
+            },
+            tunnels: config.tunnels(),
+            reverses: config.reverses(),
+            dns_listen: config.dns_listen.map(|listen| listen.to_string()),
+            context: Arc::new(Context {
+                remote_addr: config.remote_addr.to_string(),
+                key: config.key.to_string(),
+                users: config.users(),
+                fast_open: config.fast_open,
+                sniff_ports: config.sniff_ports(),
+                sniff_override: config.sniff_override,
+                early_data_timeout: match config.early_data_timeout {
+                    None => EARLY_DATA_TIMEOUT,
+                    Some(ms) => Duration::from_millis(ms.parse()?),
+                },
+                reverse_user: config.reverse_user(),
+                dns_upstream: config
+                    .dns_upstream
+                    .and_then(|upstream| http::parse_authority(upstream, 53)),
+                dns_direct: config.dns_direct.iter().map(|d| d.to_string()).collect(),
+                dns_direct_upstream: match config.dns_direct_upstream {
+                    None => None,
+                    Some(upstream) => Some(upstream.parse()?),
+                },
+                dns_cache: Mutex::new(dns::Cache::default()),
+                rules: config.rules()?,
+                direct: config.outbound(),
+            }),
         })
     }
 
@@ -33,8 +239,7 @@ impl LocalServer {
 }
 
 impl LocalServer {
-    async fn proc0(key: String, r: OwnedReadHalf, mut w: OwnedWriteHalf) {
-        let mut de = Decryption::new(key, r);
+    async fn proc0(mut de: Decryption, mut w: OwnedWriteHalf) {
         loop {
             match de.decryption_read().await {
                 Err(err) => {
@@ -50,9 +255,8 @@ impl LocalServer {
             }
         }
     }
-    async fn proc1(key: String, mut r: OwnedReadHalf, w: OwnedWriteHalf) {
+    async fn proc1(mut r: OwnedReadHalf, mut en: Encryption) {
         let mut buffer = [0_u8; 2048];
-        let mut en = Encryption::new(key, w);
         loop {
             match r.read(&mut buffer).await {
                 Err(err) => {
@@ -60,50 +264,1315 @@ impl LocalServer {
                     return;
                 }
                 Ok(n) => {
-                    if n <= 0 {
+                    if n == 0 {
                         debug!("r.read eof");
                         return;
                     }
-                    match en.encryption_write(&buffer[..n]).await {
+                    if let Err(err) = en.encryption_write(&buffer[..n]).await {
+                        debug!("en.encryption_write {:?}", err);
+                        return;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Connects to the remote and sends the request header, along with any
+    /// early data.
+    async fn open_tunnel(ctx: &Context, request: &[u8]) -> io::Result<(Encryption, Decryption)> {
+        let s1 = TcpStream::connect(&ctx.remote_addr).await?;
+        let (r1, w1) = s1.into_split();
+        let mut en = Encryption::new(ctx.key.clone(), w1);
+        let de = Decryption::new(ctx.key.clone(), r1);
+        en.encryption_write(request).await?;
+        Ok((en, de))
+    }
+
+    async fn read_reply(de: &mut Decryption, target: &Address) -> Reply {
+        let reply = match de.decryption_read().await {
+            Err(err) => Err(err),
+            Ok(reply) => Reply::from_bytes(&reply).map(|(reply, _)| reply),
+        };
+        match reply {
+            Err(err) => {
+                warn!("remote reply {} {:?}", target, err);
+                Reply::new(socks5::REP_GENERAL_FAILURE, Address::unspecified())
+            }
+            Ok(reply) => reply,
+        }
+    }
+
+    /// Serves a client on the main listener, telling SOCKS4, SOCKS5 and HTTP
+    /// apart from the first byte it sends.
+    async fn process(mut s0: TcpStream, ctx: Arc<Context>) {
+        let mut version = [0_u8; 1];
+        let inbound = match s0.peek(&mut version).await {
+            Err(err) => {
+                debug!("s0.peek {:?}", err);
+                return;
+            }
+            Ok(0) => return,
+            Ok(_) => match version[0] {
+                socks4::VERSION => Inbound::Socks4,
+                socks5::VERSION => Inbound::Socks5,
+                // first letter of the request method
+                b'A'..=b'Z' | b'a'..=b'z' => Inbound::Http,
+                _ => {
+                    warn!("unknown protocol, first byte {:#04x}", version[0]);
+                    return;
+                }
+            },
+        };
+        if inbound == Inbound::Http {
+            Self::http_process(s0, ctx).await;
+            return;
+        }
+        let request = match inbound {
+            Inbound::Socks4 => socks4::handshake(&mut s0, &ctx.users).await,
+            _ => socks5::handshake(&mut s0, &ctx.users).await,
+        };
+        let request = match request {
+            Err(err) => {
+                warn!("{:?} handshake {:?}", inbound, err);
+                return;
+            }
+            Ok(request) => request,
+        };
+
+        match request.cmd {
+            socks5::CMD_UDP_ASSOCIATE => {
+                info!(
+                    "udp associate {} user {}",
+                    request.target,
+                    request.user_name()
+                );
+                Self::udp_associate(s0, &ctx, request).await;
+            }
+            socks5::CMD_BIND => {
+                info!("bind {} user {}", request.target, request.user_name());
+                Self::bind(s0, &ctx, request).await;
+            }
+            _ => {
+                info!("connect {} user {}", request.target, request.user_name());
+                Self::connect(s0, &ctx, request, inbound, vec![], None).await;
+            }
+        }
+    }
+
+    async fn http_process(mut s0: TcpStream, ctx: Arc<Context>) {
+        let mut buffer = vec![];
+        let mut upstream = None;
+        loop {
+            let head = match http::read_head(&mut s0, &mut buffer).await {
+                Err(err) => {
+                    debug!("http::read_head {:?}", err);
+                    return;
+                }
+                Ok(head) => head,
+            };
+            if !head.method.eq_ignore_ascii_case("CONNECT") {
+                match Self::http_forward(&mut s0, &ctx, &head, &mut buffer, &mut upstream).await {
+                    Err(err) => {
+                        warn!("http forward {} {:?}", head.uri, err);
+                        return;
+                    }
+                    Ok(true) => continue,
+                    Ok(false) => return,
+                }
+            }
+
+            let request = match http::handshake(&mut s0, &head, &ctx.users).await {
+                Err(err) => {
+                    warn!("http::handshake {:?}", err);
+                    return;
+                }
+                Ok(request) => request,
+            };
+            info!("connect {} user {}", request.target, request.user_name());
+            Self::connect(s0, &ctx, request, Inbound::Http, buffer, None).await;
+            return;
+        }
+    }
+
+    /// Forwards a plain HTTP request with an absolute URI and its response.
+    /// The tunnel in `upstream` is reused when it leads to the same target,
+    /// and kept afterwards if the origin server allows. Returns whether the
+    /// client connection stays open for another request.
+    async fn http_forward(
+        s0: &mut TcpStream,
+        ctx: &Context,
+        head: &http::Head,
+        buffer: &mut Vec<u8>,
+        upstream: &mut Option<Upstream>,
+    ) -> io::Result<bool> {
+        let user = match http::authenticate(head, &ctx.users) {
+            Err(_) => {
+                s0.write_all(http::PROXY_AUTH_REQUIRED).await?;
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "authentication failed",
+                ));
+            }
+            Ok(user) => user,
+        };
+        let (target, path) = match head.absolute_target() {
+            None => {
+                s0.write_all(http::BAD_REQUEST).await?;
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "not an absolute http URI",
+                ));
+            }
+            Some(target) => target,
+        };
+        let mut body = match http::Body::request(head) {
+            Err(err) => {
+                s0.write_all(http::BAD_REQUEST).await?;
+                return Err(err);
+            }
+            Ok(body) => body,
+        };
+        let request = Request::new(socks5::CMD_CONNECT, user, target);
+        info!(
+            "http {} {} user {}",
+            head.method,
+            request.target,
+            request.user_name()
+        );
+
+        let direct = match ctx
+            .rules
+            .action(&request.target, None, Inbound::Http.name())
+            .await
+        {
+            Action::Proxy => false,
+            Action::Direct => true,
+            Action::Reject => {
+                info!("reject {}", request.target);
+                http::reply(s0, socks5::REP_CONNECTION_NOT_ALLOWED).await?;
+                return Ok(false);
+            }
+        };
+
+        if head.expects_continue() {
+            s0.write_all(http::CONTINUE).await?;
+        }
+        let origin_head = head.to_origin_form(&request.target, &path);
+        let bodyless = matches!(body, http::Body::Empty);
+
+        let mut reused = None;
+        if let Some(mut up) = upstream.take() {
+            if up.target == request.target && up.write(&origin_head).await.is_ok() {
+                reused = Some(up);
+            }
+        }
+        let is_reused = reused.is_some();
+        let mut up = match reused {
+            Some(up) => up,
+            None => Self::http_open(s0, ctx, &request, &origin_head, direct).await?,
+        };
+        Self::http_send_body(s0, buffer, &mut body, &mut up).await?;
+
+        let response = match up.read_response_head().await {
+            // the origin server may have closed an idle connection meanwhile
+            Err(err) if is_reused && bodyless && up.buffer.is_empty() => {
+                debug!("http reused tunnel {} {:?}", request.target, err);
+                up = Self::http_open(s0, ctx, &request, &origin_head, direct).await?;
+                up.read_response_head().await
+            }
+            response => response,
+        };
+        let (mut len, mut response) = match response {
+            Err(err) => {
+                http::reply(s0, socks5::REP_GENERAL_FAILURE).await?;
+                return Err(err);
+            }
+            Ok(response) => response,
+        };
+        // interim responses come before the final one
+        while response.status / 100 == 1 {
+            s0.write_all(&up.buffer[..len]).await?;
+            up.buffer.drain(..len);
+            let next = up.read_response_head().await?;
+            len = next.0;
+            response = next.1;
+        }
+        let mut body = match http::Body::response(&response, &head.method) {
+            Err(err) => {
+                http::reply(s0, socks5::REP_GENERAL_FAILURE).await?;
+                return Err(err);
+            }
+            Ok(body) => body,
+        };
+        s0.write_all(&up.buffer[..len]).await?;
+        up.buffer.drain(..len);
+
+        let until_eof = matches!(body, http::Body::UntilEof);
+        loop {
+            let (n, done) = body.feed(&up.buffer)?;
+            s0.write_all(&up.buffer[..n]).await?;
+            up.buffer.drain(..n);
+            if done {
+                break;
+            }
+            match up.read().await {
+                // closing the connection ends the body
+                Err(_) if until_eof => return Ok(false),
+                Err(err) => return Err(err),
+                Ok(()) => {}
+            }
+        }
+
+        if response.keep_alive() {
+            *upstream = Some(up);
+        }
+        Ok(head.keep_alive() && !until_eof)
+    }
+
+    /// Opens a tunnel for a plain HTTP request, its head sent as early data,
+    /// or with `direct` a connection to the origin server itself. Failures
+    /// are answered to the client.
+    async fn http_open(
+        s0: &mut TcpStream,
+        ctx: &Context,
+        request: &Request,
+        origin_head: &[u8],
+        direct: bool,
+    ) -> io::Result<Upstream> {
+        if direct {
+            info!("direct {}", request.target);
+            let mut s1 = match Self::connect_target(ctx, &request.target).await {
+                Err(err) => {
+                    warn!("direct connect {} {:?}", request.target, err);
+                    http::reply(s0, socks5::reply_code(&err)).await?;
+                    return Err(err);
+                }
+                Ok(s1) => s1,
+            };
+            s1.write_all(origin_head).await?;
+            return Ok(Upstream {
+                target: request.target.clone(),
+                link: Link::Direct(s1),
+                buffer: vec![],
+            });
+        }
+        let mut header = request.to_bytes();
+        header.extend_from_slice(origin_head);
+        let (en, mut de) = match Self::open_tunnel(ctx, &header).await {
+            Err(err) => {
+                warn!(
+                    "Unable to connect to remote server {:?} {:?}",
+                    &ctx.remote_addr, err
+                );
+                http::reply(s0, socks5::REP_GENERAL_FAILURE).await?;
+                return Err(err);
+            }
+            Ok(tunnel) => tunnel,
+        };
+        let reply = Self::read_reply(&mut de, &request.target).await;
+        if reply.rep != socks5::REP_SUCCEEDED {
+            http::reply(s0, reply.rep).await?;
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                format!(
+                    "connect {} failed, reply {:#04x}",
+                    request.target, reply.rep
+                ),
+            ));
+        }
+        Ok(Upstream {
+            target: request.target.clone(),
+            link: Link::Tunnel(en, de),
+            buffer: vec![],
+        })
+    }
+
+    /// Sends the request body to the origin server, starting with what is
+    /// already in `buffer`.
+    async fn http_send_body(
+        s0: &mut TcpStream,
+        buffer: &mut Vec<u8>,
+        body: &mut http::Body,
+        up: &mut Upstream,
+    ) -> io::Result<()> {
+        let mut chunk = [0_u8; 2048];
+        loop {
+            let (n, done) = body.feed(buffer)?;
+            if n > 0 {
+                up.write(&buffer[..n]).await?;
+                buffer.drain(..n);
+            }
+            if done {
+                return Ok(());
+            }
+            let n = s0.read(&mut chunk).await?;
+            if n == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "request body truncated",
+                ));
+            }
+            buffer.extend_from_slice(&chunk[..n]);
+        }
+    }
+
+    /// Tunnels a connection diverted by iptables to its original
+    /// destination. With TPROXY, the connection's local address already is
+    /// the original destination.
+    async fn redir_process(mut s0: TcpStream, ctx: Arc<Context>, inbound: Inbound) {
+        let target = match inbound {
+            Inbound::Redir => redir::original_dst(&s0),
+            _ => s0.local_addr(),
+        };
+        let target = match target {
+            Err(err) => {
+                warn!("{:?} original destination {:?}", inbound, err);
+                return;
+            }
+            Ok(target) => target,
+        };
+        // a client connecting to the listener itself would loop forever
+        if inbound == Inbound::Redir && s0.local_addr().ok() == Some(target) {
+            warn!("redir {} is not a redirected connection", target);
+            return;
+        }
+        let port = target.port();
+        let sniffed = if ctx
+            .sniff_ports
+            .iter()
+            .any(|(low, high)| *low <= port && port <= *high)
+        {
+            Self::sniff(&mut s0).await
+        } else {
+            Ok((None, vec![]))
+        };
+        let (host, early_data) = match sniffed {
+            Err(err) => {
+                debug!("sniff {:?}", err);
+                return;
+            }
+            Ok(sniffed) => sniffed,
+        };
+        // a client could name any host, so the name is only trusted when the
+        // DNS listener gave it the address it connected to
+        let host = host.filter(|host| {
+            let answered = match ctx.dns_cache.lock() {
+                Err(_) => false,
+                Ok(cache) => cache.answered(host, &acl::unmap(&target.ip())),
+            };
+            debug!(
+                "{:?} {} sniffed {}, answered {}",
+                inbound, target, host, answered
+            );
+            answered || ctx.sniff_override
+        });
+        // let the remote resolve, and route by, the name the client used
+        let request = match host {
+            None => Request::new(socks5::CMD_CONNECT, None, Address::Ip(target)),
+            Some(host) => Request::new(socks5::CMD_CONNECT, None, Address::Domain(host, port)),
+        };
+        info!("{:?} {}", inbound, request.target);
+        Self::connect(s0, &ctx, request, inbound, early_data, Some(target)).await;
+    }
+
+    /// Reads the first bytes of a transparent client, looking for a TLS SNI
+    /// or HTTP `Host`. Returns the host name, if any, along with the bytes
+    /// read.
+    async fn sniff(s0: &mut TcpStream) -> io::Result<(Option<String>, Vec<u8>)> {
+        let start = Instant::now();
+        let mut buffer = vec![];
+        let mut chunk = [0_u8; 2048];
+        loop {
+            match sniff::host(&buffer) {
+                Sniff::Found(host) => return Ok((Some(host), buffer)),
+                Sniff::NotFound => return Ok((None, buffer)),
+                Sniff::NeedMore => {}
+            }
+            let remaining = match SNIFF_TIMEOUT.checked_sub(start.elapsed()) {
+                None => return Ok((None, buffer)),
+                Some(remaining) => remaining,
+            };
+            match timeout(remaining, s0.read(&mut chunk)).await {
+                Ok(Err(err)) => return Err(err),
+                Ok(Ok(n)) if n > 0 => buffer.extend_from_slice(&chunk[..n]),
+                // closed, or the server speaks first
+                _ => return Ok((None, buffer)),
+            }
+        }
+    }
+
+    /// Relays UDP datagrams diverted by TPROXY. Each client address gets its
+    /// own UDP association through the tunnel.
+    async fn tproxy_udp_run(listener: tproxy::UdpListener, listen: SocketAddr, ctx: Arc<Context>) {
+        let mut sessions: HashMap<SocketAddr, (mpsc::Sender<Vec<u8>>, Instant)> = HashMap::new();
+        let mut expire = interval(UDP_IDLE_TIMEOUT);
+        let mut buffer = vec![0_u8; 65536];
+        loop {
+            let (n, src, dst) = select! {
+                _ = expire.tick() => {
+                    // dropping the sender ends the session
+                    sessions.retain(|_, (_, last)| last.elapsed() < UDP_IDLE_TIMEOUT);
+                    continue;
+                }
+                recv = listener.recv(&mut buffer) => match recv {
+                    Err(err) => {
+                        debug!("listener.recv {:?}", err);
+                        continue;
+                    }
+                    Ok(recv) => recv,
+                },
+            };
+            // sent to the listener itself, relaying it would loop forever
+            if tproxy::is_listener(&dst, &listen) {
+                debug!("tproxy udp {} is not a diverted datagram", src);
+                continue;
+            }
+            let mut frame = Address::Ip(dst).to_bytes();
+            frame.extend_from_slice(&buffer[..n]);
+
+            if let Some((tx, last)) = sessions.get_mut(&src) {
+                *last = Instant::now();
+                match tx.try_send(frame) {
+                    Err(TrySendError::Closed(data)) => frame = data,
+                    // dropped like any datagram when the tunnel lags
+                    _ => continue,
+                }
+            }
+            info!("tproxy udp {} -> {}", src, dst);
+            let (mut tx, rx) = mpsc::channel(64);
+            let _ = tx.try_send(frame);
+            sessions.insert(src, (tx, Instant::now()));
+            spawn(Self::tproxy_udp_session(ctx.clone(), src, rx));
+        }
+    }
+
+    /// Carries the datagrams of one TPROXY client through a UDP association.
+    /// Replies are sent from the address they came from, as if the client
+    /// had reached it directly.
+    async fn tproxy_udp_session(
+        ctx: Arc<Context>,
+        client: SocketAddr,
+        mut rx: mpsc::Receiver<Vec<u8>>,
+    ) {
+        let request = Request::new(socks5::CMD_UDP_ASSOCIATE, None, Address::unspecified());
+        let (mut en, mut de) = match Self::open_tunnel(&ctx, &request.to_bytes()).await {
+            Err(err) => {
+                warn!(
+                    "Unable to connect to remote server {:?} {:?}",
+                    &ctx.remote_addr, err
+                );
+                return;
+            }
+            Ok(tunnel) => tunnel,
+        };
+        let reply = Self::read_reply(&mut de, &request.target).await;
+        if reply.rep != socks5::REP_SUCCEEDED {
+            warn!("tproxy udp {} failed, reply {:#04x}", client, reply.rep);
+            return;
+        }
+
+        let mut frames = de.into_frames();
+        let mut senders: HashMap<SocketAddr, UdpSocket> = HashMap::new();
+        loop {
+            select! {
+                frame = rx.recv() => {
+                    let frame = match frame {
+                        None => return,
+                        Some(frame) => frame,
+                    };
+                    if let Err(err) = en.encryption_write(&frame).await {
+                        debug!("en.encryption_write {:?}", err);
+                        return;
+                    }
+                }
+                data = frames.recv() => {
+                    let data = match data {
+                        None => return,
+                        Some(Err(err)) => {
+                            debug!("de.decryption_read {:?}", err);
+                            return;
+                        }
+                        Some(Ok(data)) => data,
+                    };
+                    let (from, n) = match Address::from_bytes(&data) {
+                        Ok((Address::Ip(from), n)) => (from, n),
+                        _ => continue,
+                    };
+                    let sender = match senders.entry(from) {
+                        Entry::Occupied(entry) => entry.into_mut(),
+                        Entry::Vacant(entry) => match tproxy::udp_sender(&from) {
+                            Err(err) => {
+                                warn!("tproxy::udp_sender {} {:?}", from, err);
+                                continue;
+                            }
+                            Ok(sender) => entry.insert(sender),
+                        },
+                    };
+                    if let Err(err) = sender.send_to(&data[n..], &client).await {
+                        debug!("sender.send_to {:?}", err);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Tunnels a CONNECT request. `early_data` holds whatever the client
+    /// already sent past its request. `original` is where a transparent
+    /// client connected to, the target possibly being the name it sniffed.
+    async fn connect(
+        mut s0: TcpStream,
+        ctx: &Context,
+        request: Request,
+        inbound: Inbound,
+        early_data: Vec<u8>,
+        original: Option<SocketAddr>,
+    ) {
+        let ip = original.map(|original| original.ip());
+        match ctx.rules.action(&request.target, ip, inbound.name()).await {
+            Action::Proxy => {}
+            Action::Direct => {
+                // the client already picked the address
+                let target = original.map_or(request.target, Address::Ip);
+                Self::connect_direct(s0, ctx, &target, inbound, early_data).await;
+                return;
+            }
+            Action::Reject => {
+                info!("reject {}", request.target);
+                let bind = Address::unspecified();
+                let _ = inbound
+                    .reply(&mut s0, socks5::REP_CONNECTION_NOT_ALLOWED, &bind)
+                    .await;
+                return;
+            }
+        }
+
+        let fast_open = ctx.fast_open;
+        let target = request.target.clone();
+        let mut request = request.to_bytes();
+        request.extend_from_slice(&early_data);
+        if fast_open {
+            // answer before the remote has connected, so that the client's
+            // first payload (e.g. a TLS ClientHello) can travel in the same
+            // frame as the target address
+            let bind = Address::unspecified();
+            if let Err(err) = inbound.reply(&mut s0, socks5::REP_SUCCEEDED, &bind).await {
+                warn!("{:?} reply {:?}", inbound, err);
+                return;
+            }
+        }
+        if fast_open && early_data.is_empty() && ctx.early_data_timeout > Duration::from_millis(0) {
+            let mut buffer = [0_u8; 2048];
+            match timeout(ctx.early_data_timeout, s0.read(&mut buffer)).await {
+                Ok(Err(err)) => {
+                    debug!("s0.read {:?}", err);
+                    return;
+                }
+                Ok(Ok(0)) => {
+                    debug!("s0.read eof");
+                    return;
+                }
+                Ok(Ok(n)) => request.extend_from_slice(&buffer[..n]),
+                // server speaks first, nothing to bundle
+                Err(_) => {}
+            }
+        }
+
+        let (en, mut de) = match Self::open_tunnel(ctx, &request).await {
+            Err(err) => {
+                warn!(
+                    "Unable to connect to remote server {:?} {:?}",
+                    &ctx.remote_addr, err
+                );
+                if !fast_open {
+                    let bind = Address::unspecified();
+                    let _ = inbound
+                        .reply(&mut s0, socks5::REP_GENERAL_FAILURE, &bind)
+                        .await;
+                }
+                return;
+            }
+            Ok(tunnel) => tunnel,
+        };
+
+        let (r0, mut w0) = s0.into_split();
+        // in fast open mode the client is already talking
+        let mut upstream = Some((r0, en));
+        if fast_open {
+            if let Some((r0, en)) = upstream.take() {
+                spawn(Self::proc1(r0, en));
+            }
+        }
+
+        let reply = Self::read_reply(&mut de, &target).await;
+        if !fast_open {
+            if let Err(err) = inbound.reply(&mut w0, reply.rep, &reply.bind).await {
+                warn!("{:?} reply {:?}", inbound, err);
+                return;
+            }
+        }
+        if reply.rep != socks5::REP_SUCCEEDED {
+            warn!("connect {} failed, reply {:#04x}", target, reply.rep);
+            return;
+        }
+
+        spawn(Self::proc0(de, w0));
+        if let Some((r0, en)) = upstream {
+            spawn(Self::proc1(r0, en));
+        }
+    }
+
+    /// Connects to the target without the remote and relays between it and
+    /// the client.
+    async fn connect_direct(
+        mut s0: TcpStream,
+        ctx: &Context,
+        target: &Address,
+        inbound: Inbound,
+        early_data: Vec<u8>,
+    ) {
+        info!("direct {}", target);
+        let s1 = match Self::connect_target(ctx, target).await {
+            Err(err) => {
+                warn!("direct connect {} {:?}", target, err);
+                let bind = Address::unspecified();
+                let _ = inbound
+                    .reply(&mut s0, socks5::reply_code(&err), &bind)
+                    .await;
+                return;
+            }
+            Ok(s1) => s1,
+        };
+        let bind = match s1.local_addr() {
+            Err(_) => Address::unspecified(),
+            Ok(addr) => Address::Ip(addr),
+        };
+        if let Err(err) = inbound.reply(&mut s0, socks5::REP_SUCCEEDED, &bind).await {
+            warn!("{:?} reply {:?}", inbound, err);
+            return;
+        }
+
+        let (mut r0, mut w0) = s0.into_split();
+        let (mut r1, mut w1) = s1.into_split();
+        if let Err(err) = w1.write_all(&early_data).await {
+            debug!("w1.write_all {:?}", err);
+            return;
+        }
+        spawn(async move {
+            if let Err(err) = tokio::io::copy(&mut r0, &mut w1).await {
+                debug!("direct copy {:?}", err);
+            }
+        });
+        spawn(async move {
+            if let Err(err) = tokio::io::copy(&mut r1, &mut w0).await {
+                debug!("direct copy {:?}", err);
+            }
+        });
+    }
+
+    /// Connects to the target without the remote, the socket marked so
+    /// that redir and TPROXY rules can let it through.
+    async fn connect_target(ctx: &Context, target: &Address) -> io::Result<TcpStream> {
+        let addrs: Vec<SocketAddr> = match target {
+            Address::Ip(addr) => vec![*addr],
+            Address::Domain(host, port) => lookup_host((host.as_str(), *port)).await?.collect(),
+        };
+        let mut last = io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no address for {}", target),
+        );
+        for addr in addrs {
+            match ctx.direct.connect(addr, None).await {
+                Err(err) => last = err,
+                Ok(s1) => return Ok(s1),
+            }
+        }
+        Err(last)
+    }
+
+    /// Relays a SOCKS5 BIND. The remote listens for the incoming connection
+    /// and sends two replies, one once listening and one once connected.
+    async fn bind(mut s0: TcpStream, ctx: &Context, request: Request) {
+        let target = request.target.clone();
+        let (en, mut de) = match Self::open_tunnel(ctx, &request.to_bytes()).await {
+            Err(err) => {
+                warn!(
+                    "Unable to connect to remote server {:?} {:?}",
+                    &ctx.remote_addr, err
+                );
+                let bind = Address::unspecified();
+                let _ = socks5::reply(&mut s0, socks5::REP_GENERAL_FAILURE, &bind).await;
+                return;
+            }
+            Ok(tunnel) => tunnel,
+        };
+
+        for _ in 0..2 {
+            let reply = Self::read_reply(&mut de, &target).await;
+            if let Err(err) = socks5::reply(&mut s0, reply.rep, &reply.bind).await {
+                warn!("socks5::reply {:?}", err);
+                return;
+            }
+            if reply.rep != socks5::REP_SUCCEEDED {
+                warn!("bind {} failed, reply {:#04x}", target, reply.rep);
+                return;
+            }
+            debug!("bind {} reply {}", target, reply.bind);
+        }
+
+        let (r0, w0) = s0.into_split();
+        spawn(Self::proc0(de, w0));
+        spawn(Self::proc1(r0, en));
+    }
+
+    /// Relays a SOCKS5 UDP association. Each datagram travels through the
+    /// tunnel as one frame, `ATYP ADDR PORT DATA`. The association ends when
+    /// the client closes its TCP connection.
+    async fn udp_associate(mut s0: TcpStream, ctx: &Context, request: Request) {
+        let target = request.target.clone();
+        let (peer, local) = match (s0.peer_addr(), s0.local_addr()) {
+            (Ok(peer), Ok(local)) => (peer, local),
+            (Err(err), _) | (_, Err(err)) => {
+                warn!("udp_associate addr {:?}", err);
+                return;
+            }
+        };
+
+        let relay = match UdpSocket::bind(SocketAddr::new(local.ip(), 0)).await {
+            Err(err) => {
+                warn!("udp_associate bind {:?}", err);
+                let bind = Address::unspecified();
+                let _ = socks5::reply(&mut s0, socks5::REP_GENERAL_FAILURE, &bind).await;
+                return;
+            }
+            Ok(relay) => relay,
+        };
+        let bind = match relay.local_addr() {
+            Err(err) => {
+                warn!("udp_associate local_addr {:?}", err);
+                return;
+            }
+            Ok(addr) => Address::Ip(addr),
+        };
+
+        let (mut en, mut de) = match Self::open_tunnel(ctx, &request.to_bytes()).await {
+            Err(err) => {
+                warn!(
+                    "Unable to connect to remote server {:?} {:?}",
+                    &ctx.remote_addr, err
+                );
+                let unspecified = Address::unspecified();
+                let _ = socks5::reply(&mut s0, socks5::REP_GENERAL_FAILURE, &unspecified).await;
+                return;
+            }
+            Ok(tunnel) => tunnel,
+        };
+
+        let reply = Self::read_reply(&mut de, &target).await;
+        if reply.rep != socks5::REP_SUCCEEDED {
+            warn!("udp associate {} failed, reply {:#04x}", target, reply.rep);
+            let _ = socks5::reply(&mut s0, reply.rep, &reply.bind).await;
+            return;
+        }
+        if let Err(err) = socks5::reply(&mut s0, socks5::REP_SUCCEEDED, &bind).await {
+            warn!("socks5::reply {:?}", err);
+            return;
+        }
+
+        let (mut relay_r, mut relay_w) = relay.split();
+        let mut frames = de.into_frames();
+        let mut client: Option<SocketAddr> = None;
+        let mut control = [0_u8; 64];
+        let mut buffer = vec![0_u8; 65536];
+        loop {
+            select! {
+                n = s0.read(&mut control) => {
+                    match n {
+                        Err(err) => debug!("s0.read {:?}", err),
+                        Ok(0) => debug!("s0.read eof"),
+                        // nothing is expected on the control connection
+                        Ok(_) => continue,
+                    }
+                    return;
+                }
+                recv = relay_r.recv_from(&mut buffer) => {
+                    let (n, src) = match recv {
                         Err(err) => {
-                            debug!("en.encryption_write {:?}", err);
+                            debug!("relay_r.recv_from {:?}", err);
                             return;
                         }
-                        _ => {}
+                        Ok(recv) => recv,
+                    };
+                    // only the client that set up the association may use it
+                    if src.ip() != peer.ip() {
+                        debug!("udp_associate drop datagram from {}", src);
+                        continue;
+                    }
+                    if let Err(err) = socks5::parse_udp_header(&buffer[..n]) {
+                        debug!("socks5::parse_udp_header {:?}", err);
+                        continue;
+                    }
+                    client = Some(src);
+                    if let Err(err) = en.encryption_write(&buffer[3..n]).await {
+                        debug!("en.encryption_write {:?}", err);
+                        return;
+                    }
+                }
+                data = frames.recv() => {
+                    let data = match data {
+                        None => return,
+                        Some(Err(err)) => {
+                            debug!("de.decryption_read {:?}", err);
+                            return;
+                        }
+                        Some(Ok(data)) => data,
+                    };
+                    let client = match client {
+                        None => continue,
+                        Some(client) => client,
+                    };
+                    let mut datagram = vec![0x00, 0x00, 0x00];
+                    datagram.extend_from_slice(&data);
+                    if let Err(err) = relay_w.send_to(&datagram, &client).await {
+                        debug!("relay_w.send_to {:?}", err);
                     }
                 }
             }
         }
     }
-    async fn process(s0: TcpStream, key: String, remote_addr: String) {
-        match TcpStream::connect(&remote_addr).await {
-            Ok(s1) => {
-                let (r0, w0) = s0.into_split();
-                let (r1, w1) = s1.into_split();
-                let (k0, k1) = (key.clone(), key.clone());
-                spawn(Self::proc0(k0, r1, w0));
-                spawn(Self::proc1(k1, r0, w1));
+
+    /// Accepts clients on an extra listener dedicated to one protocol.
+    async fn inbound_run(mut listener: TcpListener, ctx: Arc<Context>, inbound: Inbound) {
+        loop {
+            let (s0, _) = match listener.accept().await {
+                Err(err) => {
+                    warn!("{:?} listener accept {:?}", inbound, err);
+                    continue;
+                }
+                Ok(accepted) => accepted,
+            };
+
+            debug!("{:?} client {:?}", inbound, &s0);
+
+            match inbound {
+                Inbound::Redir | Inbound::Tproxy => {
+                    spawn(Self::redir_process(s0, ctx.clone(), inbound))
+                }
+                Inbound::Dns => spawn(Self::dns_tcp_process(s0, ctx.clone())),
+                _ => spawn(Self::http_process(s0, ctx.clone())),
+            };
+        }
+    }
+
+    /// Accepts clients of a port-forward tunnel, all sent to `target`.
+    async fn tunnel_run(mut listener: TcpListener, ctx: Arc<Context>, target: Address) {
+        loop {
+            let (s0, _) = match listener.accept().await {
+                Err(err) => {
+                    warn!("tunnel listener accept {:?}", err);
+                    continue;
+                }
+                Ok(accepted) => accepted,
+            };
+
+            debug!("tunnel client {:?}", &s0);
+
+            let request = Request::new(socks5::CMD_CONNECT, None, target.clone());
+            info!("tunnel {}", request.target);
+            let ctx = ctx.clone();
+            spawn(async move {
+                Self::connect(s0, &ctx, request, Inbound::Tunnel, vec![], None).await;
+            });
+        }
+    }
+
+    /// Keeps a reverse tunnel open, reconnecting when it fails.
+    async fn reverse_run(ctx: Arc<Context>, listen: SocketAddr, target: Address) {
+        loop {
+            if let Err(err) = Self::reverse_control(&ctx, listen, &target).await {
+                warn!("reverse {} {:?}", listen, err);
+            }
+            delay_for(REVERSE_RETRY).await;
+        }
+    }
+
+    /// Asks the remote to listen on `listen`, then dials back for each
+    /// connection it announces.
+    async fn reverse_control(
+        ctx: &Arc<Context>,
+        listen: SocketAddr,
+        target: &Address,
+    ) -> io::Result<()> {
+        let user = ctx.reverse_user.as_ref().map(|(name, _)| name.clone());
+        let request = Request::new(CMD_REVERSE, user, Address::Ip(listen));
+        let mut header = request.to_bytes();
+        match &ctx.reverse_user {
+            None => header.push(0),
+            Some((_, password)) => {
+                header.push(password.len() as u8);
+                header.extend_from_slice(password.as_bytes());
+            }
+        }
+        let (mut en, mut de) = Self::open_tunnel(ctx, &header).await?;
+        let reply = Self::read_reply(&mut de, &request.target).await;
+        if reply.rep != socks5::REP_SUCCEEDED {
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                format!("reverse {} failed, reply {:#04x}", listen, reply.rep),
+            ));
+        }
+        info!("reverse {} listening, target {}", reply.bind, target);
+
+        let mut frames = de.into_frames();
+        let mut keepalive = interval(REVERSE_KEEPALIVE);
+        loop {
+            select! {
+                _ = keepalive.tick() => en.encryption_write(&[]).await?,
+                data = timeout(REVERSE_IDLE_TIMEOUT, frames.recv()) => {
+                    let data = match data {
+                        Err(_) => {
+                            return Err(io::Error::new(
+                                io::ErrorKind::TimedOut,
+                                "control connection silent",
+                            ))
+                        }
+                        Ok(None) => {
+                            return Err(io::Error::new(
+                                io::ErrorKind::UnexpectedEof,
+                                "control connection closed",
+                            ))
+                        }
+                        Ok(Some(data)) => data?,
+                    };
+                    // keepalive echo
+                    if data.is_empty() {
+                        continue;
+                    }
+                    let (peer, _) = Address::from_bytes(data.get(8..).unwrap_or(&[]))?;
+                    info!("reverse {} from {} to {}", listen, peer, target);
+                    spawn(Self::reverse_accept(
+                        ctx.clone(),
+                        request.clone(),
+                        data[..8].to_vec(),
+                        target.clone(),
+                    ));
+                }
             }
+        }
+    }
+
+    /// Connects an announced reverse tunnel connection to `target`, dialing
+    /// back to the remote with its ID.
+    async fn reverse_accept(ctx: Arc<Context>, request: Request, id: Vec<u8>, target: Address) {
+        let s1 = match &target {
+            Address::Ip(addr) => TcpStream::connect(addr).await,
+            Address::Domain(host, port) => TcpStream::connect((host.as_str(), *port)).await,
+        };
+        let s1 = match s1 {
+            Err(err) => {
+                warn!("reverse connect {} {:?}", target, err);
+                return;
+            }
+            Ok(s1) => s1,
+        };
+
+        let request = Request::new(CMD_REVERSE_ACCEPT, request.user, request.target);
+        let mut header = request.to_bytes();
+        header.extend_from_slice(&id);
+        let (en, mut de) = match Self::open_tunnel(&ctx, &header).await {
             Err(err) => {
                 warn!(
                     "Unable to connect to remote server {:?} {:?}",
-                    &remote_addr, err
+                    &ctx.remote_addr, err
                 );
                 return;
             }
+            Ok(tunnel) => tunnel,
+        };
+        let reply = Self::read_reply(&mut de, &request.target).await;
+        if reply.rep != socks5::REP_SUCCEEDED {
+            warn!(
+                "reverse {} failed, reply {:#04x}",
+                request.target, reply.rep
+            );
+            return;
+        }
+
+        let (r1, w1) = s1.into_split();
+        spawn(Self::proc0(de, w1));
+        spawn(Self::proc1(r1, en));
+    }
+
+    /// Answers a DNS query from the cache, or forwards it: directly for split
+    /// DNS domains, else through the tunnel.
+    async fn dns_query(ctx: &Context, query: &[u8]) -> Vec<u8> {
+        let question = match dns::Question::parse(query) {
+            Err(err) => {
+                debug!("dns::Question::parse {:?}", err);
+                return dns::error_response(query, dns::RCODE_FORMAT_ERROR);
+            }
+            Ok((question, _)) => question,
+        };
+        let cached = match ctx.dns_cache.lock() {
+            Err(_) => None,
+            Ok(mut cache) => cache.get(&question, dns::id(query)),
+        };
+        if let Some(response) = cached {
+            debug!("dns {} type {} cached", question.name, question.qtype);
+            return response;
+        }
+
+        let direct = ctx
+            .dns_direct
+            .iter()
+            .any(|domain| question.is_under(domain));
+        let response = async {
+            match (direct, ctx.dns_direct_upstream, &ctx.dns_upstream) {
+                (true, Some(upstream), _) => {
+                    info!("dns {} type {} direct", question.name, question.qtype);
+                    Self::dns_direct(upstream, query).await
+                }
+                (_, _, Some(upstream)) => {
+                    info!(
+                        "dns {} type {} via {}",
+                        question.name, question.qtype, upstream
+                    );
+                    Self::dns_tunnel(ctx, upstream, query).await
+                }
+                _ => {
+                    info!("dns {} type {} remote", question.name, question.qtype);
+                    Self::dns_remote(ctx, &question, query).await
+                }
+            }
+        };
+        let response = match timeout(DNS_TIMEOUT, response).await {
+            Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "timed out")),
+            Ok(response) => response,
+        };
+        match response {
+            Err(err) => {
+                warn!("dns {} {:?}", question.name, err);
+                dns::error_response(query, dns::RCODE_SERVER_FAILURE)
+            }
+            Ok(response) => {
+                if let Ok(mut cache) = ctx.dns_cache.lock() {
+                    cache.put(question, &response);
+                }
+                response
+            }
+        }
+    }
+
+    /// Sends a query over UDP to a resolver reached without the tunnel.
+    async fn dns_direct(upstream: SocketAddr, query: &[u8]) -> io::Result<Vec<u8>> {
+        let bind = match upstream {
+            SocketAddr::V4(_) => "0.0.0.0:0",
+            SocketAddr::V6(_) => "[::]:0",
+        };
+        let mut socket = UdpSocket::bind(bind).await?;
+        socket.connect(upstream).await?;
+        socket.send(query).await?;
+        let mut buffer = vec![0_u8; 65536];
+        loop {
+            let n = socket.recv(&mut buffer).await?;
+            if dns::id(&buffer[..n]) == dns::id(query) {
+                return Ok(buffer[..n].to_vec());
+            }
+        }
+    }
+
+    /// Sends a query over TCP, through the tunnel, to `upstream`.
+    async fn dns_tunnel(ctx: &Context, upstream: &Address, query: &[u8]) -> io::Result<Vec<u8>> {
+        let mut request = Request::new(socks5::CMD_CONNECT, None, upstream.clone()).to_bytes();
+        request.extend_from_slice(&(query.len() as u16).to_be_bytes());
+        request.extend_from_slice(query);
+        let (_en, mut de) = Self::open_tunnel(ctx, &request).await?;
+        let reply = Self::read_reply(&mut de, upstream).await;
+        if reply.rep != socks5::REP_SUCCEEDED {
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                format!("connect {} failed, reply {:#04x}", upstream, reply.rep),
+            ));
+        }
+        let mut buffer = vec![];
+        loop {
+            if buffer.len() >= 2 {
+                let len = u16::from_be_bytes([buffer[0], buffer[1]]) as usize;
+                if buffer.len() >= 2 + len {
+                    return Ok(buffer[2..2 + len].to_vec());
+                }
+            }
+            buffer.extend_from_slice(&de.decryption_read().await?);
+        }
+    }
+
+    /// Has the remote resolve an A or AAAA query.
+    async fn dns_remote(
+        ctx: &Context,
+        question: &dns::Question,
+        query: &[u8],
+    ) -> io::Result<Vec<u8>> {
+        if question.qtype != dns::TYPE_A && question.qtype != dns::TYPE_AAAA {
+            return Ok(dns::error_response(query, dns::RCODE_NOT_IMPLEMENTED));
+        }
+        let target = match http::parse_authority(&question.name, 0) {
+            Some(Address::Domain(host, port)) => Address::Domain(host, port),
+            _ => return Ok(dns::error_response(query, dns::RCODE_NAME_ERROR)),
+        };
+        let request = Request::new(CMD_RESOLVE, None, target);
+        let (_en, mut de) = Self::open_tunnel(ctx, &request.to_bytes()).await?;
+        let reply = Self::read_reply(&mut de, &request.target).await;
+        if reply.rep == socks5::REP_HOST_UNREACHABLE {
+            return Ok(dns::error_response(query, dns::RCODE_NAME_ERROR));
+        }
+        if reply.rep != socks5::REP_SUCCEEDED {
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                format!("resolve failed, reply {:#04x}", reply.rep),
+            ));
+        }
+        let answer = de.decryption_read().await?;
+        let ttl = match answer.get(..4) {
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "answer truncated",
+                ))
+            }
+            Some(ttl) => u32::from_be_bytes([ttl[0], ttl[1], ttl[2], ttl[3]]),
+        };
+        let mut addrs: Vec<IpAddr> = vec![];
+        let mut i = 4;
+        while i < answer.len() {
+            let (addr, n) = Address::from_bytes(&answer[i..])?;
+            if let Address::Ip(addr) = addr {
+                addrs.push(addr.ip());
+            }
+            i += n;
+        }
+        Ok(dns::address_response(query, &addrs, ttl))
+    }
+
+    /// Serves DNS over UDP, each query answered from its own task.
+    async fn dns_udp_run(socket: UdpSocket, ctx: Arc<Context>) {
+        let (mut recv, mut send) = socket.split();
+        let (tx, mut rx) = mpsc::channel::<(Vec<u8>, SocketAddr)>(64);
+        let mut buffer = vec![0_u8; 65536];
+        loop {
+            select! {
+                received = recv.recv_from(&mut buffer) => {
+                    let (n, src) = match received {
+                        Err(err) => {
+                            debug!("recv.recv_from {:?}", err);
+                            continue;
+                        }
+                        Ok(received) => received,
+                    };
+                    let query = buffer[..n].to_vec();
+                    let ctx = ctx.clone();
+                    let mut tx = tx.clone();
+                    spawn(async move {
+                        let mut response = Self::dns_query(&ctx, &query).await;
+                        dns::truncate(&mut response, dns::udp_payload_size(&query));
+                        let _ = tx.send((response, src)).await;
+                    });
+                }
+                response = rx.recv() => {
+                    if let Some((response, dst)) = response {
+                        if let Err(err) = send.send_to(&response, &dst).await {
+                            debug!("send.send_to {:?}", err);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Serves DNS over TCP, each message prefixed with its length.
+    async fn dns_tcp_process(mut s0: TcpStream, ctx: Arc<Context>) {
+        loop {
+            let mut len = [0_u8; 2];
+            if let Err(err) = s0.read_exact(&mut len).await {
+                debug!("s0.read_exact {:?}", err);
+                return;
+            }
+            let mut query = vec![0_u8; u16::from_be_bytes(len) as usize];
+            if let Err(err) = s0.read_exact(&mut query).await {
+                debug!("s0.read_exact {:?}", err);
+                return;
+            }
+            let mut response = Self::dns_query(&ctx, &query).await;
+            let mut message = (response.len() as u16).to_be_bytes().to_vec();
+            message.append(&mut response);
+            if let Err(err) = s0.write_all(&message).await {
+                debug!("s0.write_all {:?}", err);
+                return;
+            }
         }
     }
 
     async fn run(&mut self) -> Result<(), Box<dyn Error>> {
         let mut listenner = TcpListener::bind(&self.listen).await?;
+        if let Some(http_listen) = &self.http_listen {
+            let listener = TcpListener::bind(http_listen).await?;
+            spawn(Self::inbound_run(
+                listener,
+                self.context.clone(),
+                Inbound::Http,
+            ));
+        }
+        if let Some(redir_listen) = &self.redir_listen {
+            let listener = TcpListener::bind(redir_listen).await?;
+            spawn(Self::inbound_run(
+                listener,
+                self.context.clone(),
+                Inbound::Redir,
+            ));
+        }
+        if let Some(tproxy_listen) = &self.tproxy_listen {
+            let listener = tproxy::tcp_listener(tproxy_listen)?;
+            spawn(Self::inbound_run(
+                listener,
+                self.context.clone(),
+                Inbound::Tproxy,
+            ));
+            let listener = tproxy::UdpListener::bind(tproxy_listen)?;
+            spawn(Self::tproxy_udp_run(
+                listener,
+                *tproxy_listen,
+                self.context.clone(),
+            ));
+        }
+        for (listen, target) in &self.tunnels {
+            let listener = TcpListener::bind(listen).await?;
+            spawn(Self::tunnel_run(
+                listener,
+                self.context.clone(),
+                target.clone(),
+            ));
+        }
+        if let Some(dns_listen) = &self.dns_listen {
+            let socket = UdpSocket::bind(dns_listen).await?;
+            spawn(Self::dns_udp_run(socket, self.context.clone()));
+            let listener = TcpListener::bind(dns_listen).await?;
+            spawn(Self::inbound_run(
+                listener,
+                self.context.clone(),
+                Inbound::Dns,
+            ));
+        }
+        for (listen, target) in &self.reverses {
+            spawn(Self::reverse_run(
+                self.context.clone(),
+                *listen,
+                target.clone(),
+            ));
+        }
         loop {
             let (s0, _) = listenner.accept().await?;
 
             debug!("client {:?}", &s0);
 
-            let remote_addr = self.remote_addr.clone();
-            let key = self.key.clone();
-            spawn(Self::process(s0, key, remote_addr));
+            spawn(Self::process(s0, self.context.clone()));
         }
     }
 }
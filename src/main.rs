@@ -4,11 +4,24 @@ extern crate log;
 #[global_allocator]
 static ALLOC: snmalloc_rs::SnMalloc = snmalloc_rs::SnMalloc;
 
+mod acl;
+mod address;
 mod config;
 mod decryption;
+mod dns;
 mod encryption;
+mod http;
 mod local_server;
+mod outbound;
+mod redir;
 mod remote_server;
+mod request;
+mod resolver;
+mod rules;
+mod sniff;
+mod socks4;
+mod socks5;
+mod tproxy;
 
 use crate::config::Config;
 use clap::{crate_version, App, AppSettings, Arg, SubCommand};
@@ -31,7 +44,7 @@ fn main() {
                         .long("listen")
                         .default_value("127.0.0.1:6355")
                         .required(true)
-                        .help("Listen on address"),
+                        .help("Listen on address for SOCKS4, SOCKS5 and HTTP proxy clients"),
                 )
                 .arg(
                     Arg::with_name("remote-addr")
@@ -48,6 +61,134 @@ fn main() {
                         .default_value("")
                         .required(true)
                         .help("key"),
+                )
+                .arg(
+                    Arg::with_name("user")
+                        .short("u")
+                        .long("user")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("Require SOCKS5 authentication, `name:password`, may be repeated"),
+                )
+                .arg(
+                    Arg::with_name("http-listen")
+                        .long("http-listen")
+                        .takes_value(true)
+                        .help("Also listen on address for HTTP proxy clients only"),
+                )
+                .arg(
+                    Arg::with_name("redir-listen")
+                        .long("redir-listen")
+                        .takes_value(true)
+                        .help("Also listen on address for connections sent by iptables REDIRECT"),
+                )
+                .arg(
+                    Arg::with_name("tproxy-listen")
+                        .long("tproxy-listen")
+                        .takes_value(true)
+                        .help("Also listen on address, TCP and UDP, for traffic sent by iptables TPROXY"),
+                )
+                .arg(
+                    Arg::with_name("sniff-ports")
+                        .long("sniff-ports")
+                        .takes_value(true)
+                        .default_value("80,443")
+                        .help("Ports whose redir and TPROXY connections are sniffed for a TLS SNI or HTTP Host, `port[-port],...`, empty for none. Other connections are relayed without waiting for the client to speak"),
+                )
+                .arg(
+                    Arg::with_name("sniff-override")
+                        .long("sniff-override")
+                        .help("Send sniffed host names even when the DNS listener has not answered them with the original destination, which lets clients reach any host through an allowed address"),
+                )
+                .arg(
+                    Arg::with_name("tunnel")
+                        .long("tunnel")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("Forward connections to a fixed target, `listen=host:port`, may be repeated"),
+                )
+                .arg(
+                    Arg::with_name("reverse")
+                        .long("reverse")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("Have the remote listen and send connections back to a target, `remote-ip:port=host:port`, may be repeated"),
+                )
+                .arg(
+                    Arg::with_name("reverse-user")
+                        .long("reverse-user")
+                        .takes_value(true)
+                        .help("User reverse tunnels are opened as, `name:password`, checked by the remote against its `--reverse-user`"),
+                )
+                .arg(
+                    Arg::with_name("dns-listen")
+                        .long("dns-listen")
+                        .takes_value(true)
+                        .help("Also listen on address, UDP and TCP, for DNS queries resolved through the remote"),
+                )
+                .arg(
+                    Arg::with_name("dns-upstream")
+                        .long("dns-upstream")
+                        .takes_value(true)
+                        .help("Forward DNS queries over TCP to this resolver, through the remote, instead of having the remote resolve them"),
+                )
+                .arg(
+                    Arg::with_name("dns-direct")
+                        .long("dns-direct")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .requires("dns-direct-upstream")
+                        .help("Resolve this domain and its subdomains without the remote, may be repeated"),
+                )
+                .arg(
+                    Arg::with_name("dns-direct-upstream")
+                        .long("dns-direct-upstream")
+                        .takes_value(true)
+                        .help("Resolver `ip:port` for `dns-direct` domains"),
+                )
+                .arg(
+                    Arg::with_name("early-data-timeout")
+                        .long("early-data-timeout")
+                        .takes_value(true)
+                        .help("Milliseconds fast open waits for the client's first payload, defaults to 20, 0 not waiting at all"),
+                )
+                .arg(
+                    Arg::with_name("rule")
+                        .long("rule")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("Route matching TCP connections, `kind:value=direct|proxy|reject`, the kinds being domain, domain-suffix, domain-keyword, domain-regex, ip-cidr, port and inbound, `ip-cidr:cidr,resolve` also looking domains up locally, may be repeated; UDP and DNS always go through the remote"),
+                )
+                .arg(
+                    Arg::with_name("rule-file")
+                        .long("rule-file")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("Read rules from a file, one per line, `path[=action]` giving the action of lines without one, may be repeated"),
+                )
+                .arg(
+                    Arg::with_name("rule-default")
+                        .long("rule-default")
+                        .takes_value(true)
+                        .possible_values(&["direct", "proxy", "reject"])
+                        .help("Route of connections no rule matches, `proxy` by default"),
+                )
+                .arg(
+                    Arg::with_name("direct-mark")
+                        .long("direct-mark")
+                        .takes_value(true)
+                        .help("Firewall mark of connections routed direct, so that redir and TPROXY rules can skip them"),
+                )
+                .arg(
+                    Arg::with_name("fast-open")
+                        .long("fast-open")
+                        .help("Reply to clients before the remote connects and send their first payload along with the request, saving a round trip. Clients are then told the connect succeeded, from an unspecified address, even when the target turns out unreachable; without it they get the remote's real reply"),
                 ),
         )
         .subcommand(
@@ -68,6 +209,156 @@ fn main() {
                         .default_value("")
                         .required(true)
                         .help("key"),
+                )
+                .arg(
+                    Arg::with_name("reverse-user")
+                        .long("reverse-user")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("User that may open reverse tunnels, `name:password`, may be repeated"),
+                )
+                .arg(
+                    Arg::with_name("reverse-allow")
+                        .long("reverse-allow")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("Let a user open reverse tunnels on ports, `name:port[-port]`, `*` for any user, may be repeated"),
+                )
+                .arg(
+                    Arg::with_name("resolver")
+                        .long("resolver")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("Name server `ip[:port]` to resolve targets with, tried in order, may be repeated, defaults to those of /etc/resolv.conf"),
+                )
+                .arg(
+                    Arg::with_name("hosts-file")
+                        .long("hosts-file")
+                        .takes_value(true)
+                        .help("Hosts file looked up before the name servers, empty for none, defaults to /etc/hosts"),
+                )
+                .arg(
+                    Arg::with_name("resolve-timeout")
+                        .long("resolve-timeout")
+                        .takes_value(true)
+                        .help("Seconds to wait for each name server, defaults to 5"),
+                )
+                .arg(
+                    Arg::with_name("resolve-prefer")
+                        .long("resolve-prefer")
+                        .takes_value(true)
+                        .possible_values(&["ipv4", "ipv6", "ipv4-only", "ipv6-only"])
+                        .help("Address family tried first, or the only one looked up, defaults to ipv4"),
+                )
+                .arg(
+                    Arg::with_name("bind-v4")
+                        .long("bind-v4")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("Source address of IPv4 connections to targets, may be repeated to make a pool"),
+                )
+                .arg(
+                    Arg::with_name("bind-v6")
+                        .long("bind-v6")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("Source address of IPv6 connections to targets, may be repeated to make a pool"),
+                )
+                .arg(
+                    Arg::with_name("bind-pick")
+                        .long("bind-pick")
+                        .takes_value(true)
+                        .possible_values(&["round-robin", "random", "sticky-destination", "sticky-user"])
+                        .help("How source addresses are picked from a pool, defaults to round-robin"),
+                )
+                .arg(
+                    Arg::with_name("bind-device")
+                        .long("bind-device")
+                        .takes_value(true)
+                        .help("Network device connections to targets go out of"),
+                )
+                .arg(
+                    Arg::with_name("mark")
+                        .long("mark")
+                        .takes_value(true)
+                        .help("Firewall mark of connections to targets, for policy routing"),
+                )
+                .arg(
+                    Arg::with_name("user-outbound")
+                        .long("user-outbound")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("Override the above for a user, `name:key=value[,key=value]` with keys bind-v4, bind-v6, pick, device and mark, `-` for anonymous, may be repeated"),
+                )
+                .arg(
+                    Arg::with_name("allow-cidr")
+                        .long("allow-cidr")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("Let targets be in this network, `ip[/prefix]`, even a private, loopback or link-local one, which are denied by default, may be repeated"),
+                )
+                .arg(
+                    Arg::with_name("deny-cidr")
+                        .long("deny-cidr")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("Deny targets in this network, `ip[/prefix]`, over any allow, may be repeated"),
+                )
+                .arg(
+                    Arg::with_name("allow-port")
+                        .long("allow-port")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("Only let targets be on these ports, `port[-port]`, may be repeated"),
+                )
+                .arg(
+                    Arg::with_name("deny-port")
+                        .long("deny-port")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("Deny targets on these ports, `port[-port]`, may be repeated"),
+                )
+                .arg(
+                    Arg::with_name("allow-client")
+                        .long("allow-client")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("Only accept clients from this network, `ip[/prefix]`, may be repeated"),
+                )
+                .arg(
+                    Arg::with_name("ban-after")
+                        .long("ban-after")
+                        .takes_value(true)
+                        .help("Ban client addresses after this many failed handshakes within `ban-window`"),
+                )
+                .arg(
+                    Arg::with_name("ban-window")
+                        .long("ban-window")
+                        .takes_value(true)
+                        .help("Seconds failed handshakes are counted over, defaults to 600"),
+                )
+                .arg(
+                    Arg::with_name("ban-time")
+                        .long("ban-time")
+                        .takes_value(true)
+                        .help("Seconds a ban lasts, defaults to 3600"),
+                )
+                .arg(
+                    Arg::with_name("ban-file")
+                        .long("ban-file")
+                        .takes_value(true)
+                        .help("File bans are kept in across restarts"),
                 ),
         )
         .setting(AppSettings::SubcommandRequired)
@@ -77,8 +368,46 @@ fn main() {
             let listen = arg_matcher.value_of("listen").unwrap();
             let remote_addr = arg_matcher.value_of("remote-addr").unwrap();
             let key = arg_matcher.value_of("key").unwrap();
+            let users = arg_matcher
+                .values_of("user")
+                .map(|v| v.collect())
+                .unwrap_or_default();
+
+            let fast_open = arg_matcher.is_present("fast-open");
 
-            let config = Config::new_local_server(listen, remote_addr, key);
+            let mut config = Config::new_local_server(listen, remote_addr, key, users, fast_open);
+            config.http_listen = arg_matcher.value_of("http-listen");
+            config.redir_listen = arg_matcher.value_of("redir-listen");
+            config.tproxy_listen = arg_matcher.value_of("tproxy-listen");
+            config.sniff_ports = arg_matcher.value_of("sniff-ports");
+            config.sniff_override = arg_matcher.is_present("sniff-override");
+            config.tunnels = arg_matcher
+                .values_of("tunnel")
+                .map(|v| v.collect())
+                .unwrap_or_default();
+            config.reverses = arg_matcher
+                .values_of("reverse")
+                .map(|v| v.collect())
+                .unwrap_or_default();
+            config.reverse_user = arg_matcher.value_of("reverse-user");
+            config.dns_listen = arg_matcher.value_of("dns-listen");
+            config.dns_upstream = arg_matcher.value_of("dns-upstream");
+            config.dns_direct = arg_matcher
+                .values_of("dns-direct")
+                .map(|v| v.collect())
+                .unwrap_or_default();
+            config.dns_direct_upstream = arg_matcher.value_of("dns-direct-upstream");
+            config.early_data_timeout = arg_matcher.value_of("early-data-timeout");
+            config.rules = arg_matcher
+                .values_of("rule")
+                .map(|v| v.collect())
+                .unwrap_or_default();
+            config.rule_files = arg_matcher
+                .values_of("rule-file")
+                .map(|v| v.collect())
+                .unwrap_or_default();
+            config.rule_default = arg_matcher.value_of("rule-default");
+            config.mark = arg_matcher.value_of("direct-mark");
 
             LocalServer::new(config)
                 .unwrap_or_else(|e| {
@@ -95,7 +424,61 @@ fn main() {
             let listen = arg_matcher.value_of("listen").unwrap();
             let key = arg_matcher.value_of("key").unwrap();
 
-            let config = Config::new_remote_server(listen, key);
+            let mut config = Config::new_remote_server(listen, key);
+            config.reverse_users = arg_matcher
+                .values_of("reverse-user")
+                .map(|v| v.collect())
+                .unwrap_or_default();
+            config.reverse_allow = arg_matcher
+                .values_of("reverse-allow")
+                .map(|v| v.collect())
+                .unwrap_or_default();
+            config.resolvers = arg_matcher
+                .values_of("resolver")
+                .map(|v| v.collect())
+                .unwrap_or_default();
+            config.hosts_file = arg_matcher.value_of("hosts-file");
+            config.resolve_timeout = arg_matcher.value_of("resolve-timeout");
+            config.resolve_prefer = arg_matcher.value_of("resolve-prefer");
+            config.bind_v4 = arg_matcher
+                .values_of("bind-v4")
+                .map(|v| v.collect())
+                .unwrap_or_default();
+            config.bind_v6 = arg_matcher
+                .values_of("bind-v6")
+                .map(|v| v.collect())
+                .unwrap_or_default();
+            config.bind_pick = arg_matcher.value_of("bind-pick");
+            config.bind_device = arg_matcher.value_of("bind-device");
+            config.mark = arg_matcher.value_of("mark");
+            config.user_outbounds = arg_matcher
+                .values_of("user-outbound")
+                .map(|v| v.collect())
+                .unwrap_or_default();
+            config.allow_cidrs = arg_matcher
+                .values_of("allow-cidr")
+                .map(|v| v.collect())
+                .unwrap_or_default();
+            config.deny_cidrs = arg_matcher
+                .values_of("deny-cidr")
+                .map(|v| v.collect())
+                .unwrap_or_default();
+            config.allow_ports = arg_matcher
+                .values_of("allow-port")
+                .map(|v| v.collect())
+                .unwrap_or_default();
+            config.deny_ports = arg_matcher
+                .values_of("deny-port")
+                .map(|v| v.collect())
+                .unwrap_or_default();
+            config.allow_clients = arg_matcher
+                .values_of("allow-client")
+                .map(|v| v.collect())
+                .unwrap_or_default();
+            config.ban_after = arg_matcher.value_of("ban-after");
+            config.ban_window = arg_matcher.value_of("ban-window");
+            config.ban_time = arg_matcher.value_of("ban-time");
+            config.ban_file = arg_matcher.value_of("ban-file");
 
             RemoteServer::new(config)
                 .unwrap_or_else(|e| {
@@ -0,0 +1,219 @@
+use openssl::rand::rand_bytes;
+use socket2::{Domain, SockAddr, Socket, Type};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::net::{TcpStream, UdpSocket};
+
+/// How a source address is picked from a pool.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Pick {
+    RoundRobin,
+    Random,
+    // the same target host always leaves from the same address
+    StickyDestination,
+    // each user always leaves from the same address
+    StickyUser,
+}
+
+impl Pick {
+    pub fn parse(s: &str) -> Option<Pick> {
+        match s {
+            "round-robin" => Some(Pick::RoundRobin),
+            "random" => Some(Pick::Random),
+            "sticky-destination" => Some(Pick::StickyDestination),
+            "sticky-user" => Some(Pick::StickyUser),
+            _ => None,
+        }
+    }
+}
+
+/// How connections to targets leave the remote: the pools of source
+/// addresses for each family, the device and the firewall mark, each left to
+/// the system when unset.
+#[derive(Clone, Debug, Default)]
+pub struct Outbound {
+    pub bind_v4: Vec<Ipv4Addr>,
+    pub bind_v6: Vec<Ipv6Addr>,
+    // round-robin when unset
+    pub pick: Option<Pick>,
+    pub device: Option<String>,
+    pub mark: Option<u32>,
+    // round-robin position, shared by clones
+    next: Arc<AtomicUsize>,
+}
+
+impl Outbound {
+    /// Parses `key=value[,key=value]`, the keys being `bind-v4`, `bind-v6`,
+    /// which may repeat to make a pool, `pick`, `device` and `mark`.
+    pub fn parse(options: &str) -> Option<Outbound> {
+        let mut outbound = Outbound::default();
+        for option in options.split(',') {
+            let i = option.find('=')?;
+            match (&option[..i], &option[i + 1..]) {
+                ("bind-v4", value) => outbound.bind_v4.push(value.parse().ok()?),
+                ("bind-v6", value) => outbound.bind_v6.push(value.parse().ok()?),
+                ("pick", value) => outbound.pick = Some(Pick::parse(value)?),
+                ("device", value) if !value.is_empty() && value.len() < libc::IFNAMSIZ => {
+                    outbound.device = Some(value.to_string())
+                }
+                ("mark", value) => outbound.mark = Some(value.parse().ok()?),
+                _ => return None,
+            }
+        }
+        Some(outbound)
+    }
+
+    /// These settings, falling back to `defaults` for those unset.
+    pub fn or(&self, defaults: &Outbound) -> Outbound {
+        Outbound {
+            bind_v4: if self.bind_v4.is_empty() {
+                defaults.bind_v4.clone()
+            } else {
+                self.bind_v4.clone()
+            },
+            bind_v6: if self.bind_v6.is_empty() {
+                defaults.bind_v6.clone()
+            } else {
+                self.bind_v6.clone()
+            },
+            pick: self.pick.or(defaults.pick),
+            device: self.device.clone().or_else(|| defaults.device.clone()),
+            mark: self.mark.or(defaults.mark),
+            next: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Picks the source address of a connection to `destination`, a target
+    /// host, from the pool of its family. A UDP association, having no
+    /// single destination, passes `None` and sticks to nothing but its user.
+    pub fn source(&self, ipv6: bool, destination: Option<&str>, user: &str) -> Option<IpAddr> {
+        let len = if ipv6 {
+            self.bind_v6.len()
+        } else {
+            self.bind_v4.len()
+        };
+        if len == 0 {
+            return None;
+        }
+        let i = match (self.pick.unwrap_or(Pick::RoundRobin), destination) {
+            (Pick::StickyDestination, Some(destination)) => hash(destination) % len,
+            (Pick::StickyUser, _) => hash(user) % len,
+            (Pick::Random, _) => {
+                let mut random = [0_u8; 8];
+                match rand_bytes(&mut random) {
+                    Err(_) => 0,
+                    Ok(_) => u64::from_be_bytes(random) as usize % len,
+                }
+            }
+            _ => self.next.fetch_add(1, Ordering::Relaxed) % len,
+        };
+        if ipv6 {
+            Some(IpAddr::V6(self.bind_v6[i]))
+        } else {
+            Some(IpAddr::V4(self.bind_v4[i]))
+        }
+    }
+
+    fn socket(&self, ipv6: bool, source: Option<IpAddr>, ty: Type) -> io::Result<Socket> {
+        let domain = if ipv6 { Domain::ipv6() } else { Domain::ipv4() };
+        let socket = Socket::new(domain, ty, None)?;
+        if let Some(device) = &self.device {
+            bind_device(&socket, device)?;
+        }
+        if let Some(mark) = self.mark {
+            set_mark(&socket, mark)?;
+        }
+        if let Some(ip) = source {
+            socket.bind(&SockAddr::from(SocketAddr::new(ip, 0)))?;
+        }
+        Ok(socket)
+    }
+
+    /// Connects from `source`, a pick of `source()`.
+    pub async fn connect(&self, addr: SocketAddr, source: Option<IpAddr>) -> io::Result<TcpStream> {
+        if source.is_none() && self.device.is_none() && self.mark.is_none() {
+            return TcpStream::connect(addr).await;
+        }
+        let socket = self.socket(addr.is_ipv6(), source, Type::stream())?;
+        TcpStream::connect_std(socket.into_tcp_stream(), &addr).await
+    }
+
+    /// Binds a UDP socket for one family on an ephemeral port of `source`, a
+    /// pick of `source()`, or of any address.
+    pub fn udp_bind(&self, ipv6: bool, source: Option<IpAddr>) -> io::Result<UdpSocket> {
+        let socket = self.socket(ipv6, source, Type::dgram())?;
+        if source.is_none() {
+            let any = if ipv6 {
+                IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+            } else {
+                IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+            };
+            socket.bind(&SockAddr::from(SocketAddr::new(any, 0)))?;
+        }
+        socket.set_nonblocking(true)?;
+        UdpSocket::from_std(socket.into_udp_socket())
+    }
+}
+
+fn hash(key: &str) -> usize {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish() as usize
+}
+
+#[cfg(target_os = "linux")]
+fn bind_device(socket: &Socket, device: &str) -> io::Result<()> {
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_BINDTODEVICE,
+            device.as_ptr() as *const libc::c_void,
+            device.len() as libc::socklen_t,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Marks the socket's packets for policy routing. Needs `CAP_NET_ADMIN`.
+#[cfg(target_os = "linux")]
+fn set_mark(socket: &Socket, mark: u32) -> io::Result<()> {
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_MARK,
+            &mark as *const _ as *const libc::c_void,
+            mem::size_of_val(&mark) as libc::socklen_t,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn bind_device(_socket: &Socket, _device: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "SO_BINDTODEVICE is only available on Linux",
+    ))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_mark(_socket: &Socket, _mark: u32) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "SO_MARK is only available on Linux",
+    ))
+}
@@ -0,0 +1,72 @@
+use std::io;
+use std::mem;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::os::unix::io::AsRawFd;
+use tokio::net::TcpStream;
+
+/// Returns the destination a connection had before iptables `REDIRECT`
+/// rewrote it to the local listener.
+#[cfg(target_os = "linux")]
+pub fn original_dst(s: &TcpStream) -> io::Result<SocketAddr> {
+    // IPv4 clients of a dual stack listener still go through the IPv4 table
+    let ipv4 = match s.local_addr()? {
+        SocketAddr::V4(_) => true,
+        SocketAddr::V6(addr) => addr.ip().segments()[..6] == [0, 0, 0, 0, 0, 0xffff],
+    };
+    let (level, name) = if ipv4 {
+        (libc::SOL_IP, libc::SO_ORIGINAL_DST)
+    } else {
+        (libc::SOL_IPV6, libc::IP6T_SO_ORIGINAL_DST)
+    };
+    unsafe {
+        let mut addr: libc::sockaddr_storage = mem::zeroed();
+        let mut len = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        let ret = libc::getsockopt(
+            s.as_raw_fd(),
+            level,
+            name,
+            &mut addr as *mut _ as *mut libc::c_void,
+            &mut len,
+        );
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        to_socket_addr(&addr)
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn original_dst(_s: &TcpStream) -> io::Result<SocketAddr> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "SO_ORIGINAL_DST is only available on Linux",
+    ))
+}
+
+/// Converts a `sockaddr_in` or `sockaddr_in6` filled in by the kernel.
+pub fn to_socket_addr(addr: &libc::sockaddr_storage) -> io::Result<SocketAddr> {
+    match addr.ss_family as libc::c_int {
+        libc::AF_INET => {
+            let addr = unsafe { &*(addr as *const _ as *const libc::sockaddr_in) };
+            let ip = Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr));
+            Ok(SocketAddr::V4(SocketAddrV4::new(
+                ip,
+                u16::from_be(addr.sin_port),
+            )))
+        }
+        libc::AF_INET6 => {
+            let addr = unsafe { &*(addr as *const _ as *const libc::sockaddr_in6) };
+            let ip = Ipv6Addr::from(addr.sin6_addr.s6_addr);
+            Ok(SocketAddr::V6(SocketAddrV6::new(
+                ip,
+                u16::from_be(addr.sin6_port),
+                addr.sin6_flowinfo,
+                addr.sin6_scope_id,
+            )))
+        }
+        family => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unsupported address family {}", family),
+        )),
+    }
+}
@@ -1,27 +1,181 @@
+use crate::acl;
+use crate::acl::{Bans, Cidr, Egress};
+use crate::address::Address;
 use crate::config::Config;
 use crate::decryption::Decryption;
 use crate::encryption::Encryption;
-use bytes::Buf;
+use crate::outbound::Outbound;
+use crate::request::{Reply, Request, CMD_RESOLVE, CMD_REVERSE, CMD_REVERSE_ACCEPT};
+use crate::resolver;
+use crate::resolver::Resolver;
+use crate::socks5;
+use openssl::rand::rand_bytes;
+use std::collections::HashMap;
 use std::error::Error;
-use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::future;
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::Poll;
+use std::time::{Duration, Instant};
+use tokio::future::poll_fn;
 use tokio::net::tcp::OwnedReadHalf;
 use tokio::net::tcp::OwnedWriteHalf;
+use tokio::net::udp::{RecvHalf, SendHalf};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::prelude::*;
 use tokio::runtime::Runtime;
-use tokio::spawn;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{delay_for, interval, timeout};
+use tokio::{select, spawn};
+
+// How long a BIND waits for the incoming connection.
+const BIND_TIMEOUT: Duration = Duration::from_secs(120);
+
+// How long a UDP association keeps an idle destination in its NAT table.
+const UDP_IDLE_TIMEOUT: Duration = Duration::from_secs(120);
+
+// How many datagrams of a UDP association may wait for their destination to
+// be resolved, the rest being dropped.
+const UDP_RESOLVE_QUEUE: usize = 16;
+
+// How long a connection attempt to one address of a target gets before the
+// next address is tried alongside it, as RFC 8305 recommends.
+const CONNECT_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+// How long a client has to send its first frame.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+
+// How long handshake failures of a client address are counted, and how long
+// enough of them get it banned, by default.
+const BAN_WINDOW: Duration = Duration::from_secs(600);
+const BAN_TIME: Duration = Duration::from_secs(3600);
+
+// How long the resolver waits for each name server by default.
+const RESOLVE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// How long an accepted reverse tunnel connection waits for the local to dial
+// back.
+const REVERSE_ACCEPT_TIMEOUT: Duration = Duration::from_secs(30);
+
+// How long a reverse tunnel control connection may stay silent, the local
+// sending keepalives more often.
+const REVERSE_IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+
+// A connection attempt to one address of a target.
+type Attempt = Pin<Box<dyn Future<Output = (SocketAddr, io::Result<TcpStream>)> + Send>>;
+
+/// Settings and state shared by every client connection.
+struct Context {
+    key: String,
+    resolver: Resolver,
+    egress: Egress,
+    // networks clients may connect from, any when empty
+    allow_clients: Vec<Cidr>,
+    bans: Mutex<Bans>,
+    outbound: Outbound,
+    // by user name, falling back to `outbound` for what they leave unset
+    user_outbounds: HashMap<String, Outbound>,
+    // passwords of the users that may open reverse tunnels, by name
+    reverse_users: HashMap<String, String>,
+    reverse_allow: Vec<(String, u16, u16)>,
+    // reverse tunnel connections waiting for the local to dial back, by ID
+    pending: Mutex<HashMap<u64, oneshot::Sender<(Encryption, Decryption)>>>,
+}
+
+impl Context {
+    fn client_allowed(&self, ip: &IpAddr) -> bool {
+        if !self.allow_clients.is_empty()
+            && !self.allow_clients.iter().any(|cidr| cidr.contains(ip))
+        {
+            return false;
+        }
+        match self.bans.lock() {
+            Err(_) => true,
+            Ok(mut bans) => !bans.banned(ip),
+        }
+    }
+
+    fn client_failed(&self, ip: IpAddr) {
+        if let Ok(mut bans) = self.bans.lock() {
+            bans.fail(ip);
+        }
+    }
+
+    fn outbound(&self, user: &str) -> &Outbound {
+        self.user_outbounds.get(user).unwrap_or(&self.outbound)
+    }
+
+    /// Whether the user, once its `password` is checked, may open a reverse
+    /// tunnel on `port`. Anonymous users need no password.
+    fn reverse_allowed(&self, user: Option<&str>, password: &[u8], port: u16) -> bool {
+        let user = match user {
+            None => "-",
+            Some(user) => match self.reverse_users.get(user) {
+                Some(expected) if socks5::password_eq(expected.as_bytes(), password) => user,
+                _ => return false,
+            },
+        };
+        self.reverse_allow
+            .iter()
+            .any(|(name, low, high)| (name == "*" || name == user) && *low <= port && port <= *high)
+    }
+}
 
 pub struct RemoteServer {
     listen: String,
-    key: String,
+    context: Arc<Context>,
 }
 
 impl RemoteServer {
     pub fn new(config: Config) -> Result<RemoteServer, Box<dyn Error>> {
         config.verification()?;
+        let resolver = Resolver::new(
+            config.resolvers(),
+            config.hosts_file.unwrap_or(resolver::HOSTS_FILE),
+            match config.resolve_timeout {
+                None => RESOLVE_TIMEOUT,
+                Some(secs) => Duration::from_secs(secs.parse()?),
+            },
+            match config.resolve_prefer {
+                None => resolver::Prefer::Ipv4,
+                Some(prefer) => resolver::Prefer::parse(prefer).unwrap_or(resolver::Prefer::Ipv4),
+            },
+        )?;
+        let secs = |value: Option<&str>, default: Duration| match value {
+            None => Ok(default),
+            Some(secs) => secs.parse().map(Duration::from_secs),
+        };
+        let bans = Bans::new(
+            match config.ban_after {
+                None => 0,
+                Some(failures) => failures.parse()?,
+            },
+            secs(config.ban_window, BAN_WINDOW)?,
+            secs(config.ban_time, BAN_TIME)?,
+            config.ban_file.map(|file| file.to_string()),
+        );
+        let outbound = config.outbound();
+        let user_outbounds = config
+            .user_outbounds()
+            .into_iter()
+            .map(|(user, user_outbound)| (user, user_outbound.or(&outbound)))
+            .collect();
         Ok(RemoteServer {
             listen: config.listen.to_string(),
-            key: config.key.to_string(),
+            context: Arc::new(Context {
+                key: config.key.to_string(),
+                resolver,
+                egress: config.egress(),
+                allow_clients: config.allow_clients(),
+                bans: Mutex::new(bans),
+                outbound,
+                user_outbounds,
+                reverse_users: config.reverse_users(),
+                reverse_allow: config.reverse_allow(),
+                pending: Mutex::new(HashMap::new()),
+            }),
         })
     }
 
@@ -32,149 +186,725 @@ impl RemoteServer {
 }
 
 impl RemoteServer {
-    async fn socks5_host_connect(client_de: &mut Decryption) -> io::Result<TcpStream> {
-        let mut host_len_buf = [0_u8; 1];
-        client_de.decryption_read_exact(&mut host_len_buf).await?;
-        let host_len = (&host_len_buf[..]).get_u8() as usize;
-        // host
-        let mut host = vec![0_u8; host_len];
-        client_de.decryption_read_exact(&mut host).await?;
+    /// Addresses of the target the egress policy lets us reach, checked
+    /// after resolution so that a name can't be made to point inside.
+    async fn resolve(ctx: &Context, target: &Address) -> io::Result<Vec<SocketAddr>> {
+        let addrs = match target {
+            Address::Ip(addr) => vec![*addr],
+            Address::Domain(host, port) => {
+                let (ips, _) = ctx.resolver.lookup(host).await?;
+                ips.into_iter()
+                    .map(|ip| SocketAddr::new(ip, *port))
+                    .collect()
+            }
+        };
+        let (allowed, denied): (Vec<SocketAddr>, Vec<SocketAddr>) =
+            addrs.into_iter().partition(|addr| ctx.egress.allowed(addr));
+        if allowed.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("{} {:?} denied", target, denied),
+            ));
+        }
+        if !denied.is_empty() {
+            debug!("resolve {} {:?} denied", target, denied);
+        }
+        Ok(allowed)
+    }
+
+    /// Connects to the target the Happy Eyeballs way: attempts to its
+    /// addresses, families alternating, start `CONNECT_ATTEMPT_DELAY` apart
+    /// or as soon as the previous one fails, and the first to succeed wins.
+    /// The other attempts are dropped, which cancels them.
+    async fn connect(ctx: &Context, target: &Address, user: &str) -> io::Result<TcpStream> {
+        let outbound = ctx.outbound(user);
+        let destination = match target {
+            Address::Ip(addr) => addr.ip().to_string(),
+            Address::Domain(host, _) => host.clone(),
+        };
+        let mut addrs = interleave(Self::resolve(ctx, target).await?).into_iter();
+        let mut attempts: Vec<Attempt> = vec![];
+        let mut last = None;
+        let mut next = addrs.next();
+        let mut stagger = delay_for(CONNECT_ATTEMPT_DELAY);
+        let mut start = true;
+        loop {
+            if let (true, Some(addr)) = (start, next) {
+                let source = outbound.source(addr.is_ipv6(), Some(&destination), user);
+                let outbound = outbound.clone();
+                attempts.push(Box::pin(async move {
+                    (addr, outbound.connect(addr, source).await)
+                }));
+                stagger = delay_for(CONNECT_ATTEMPT_DELAY);
+                next = addrs.next();
+            }
+            if attempts.is_empty() {
+                return Err(
+                    last.unwrap_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no address"))
+                );
+            }
 
-        // port
-        let mut port_buf = [0_u8; 2];
-        client_de.decryption_read_exact(&mut port_buf).await?;
+            // the first attempt to finish, or `None` when the next is due
+            let waiting = next.is_some();
+            let finished = poll_fn(|cx| {
+                for i in 0..attempts.len() {
+                    if let Poll::Ready(finished) = attempts[i].as_mut().poll(cx) {
+                        drop(attempts.swap_remove(i));
+                        return Poll::Ready(Some(finished));
+                    }
+                }
+                if waiting && Pin::new(&mut stagger).poll(cx).is_ready() {
+                    return Poll::Ready(None);
+                }
+                Poll::Pending
+            })
+            .await;
+            start = match finished {
+                // the delay is up
+                None => true,
+                Some((addr, Ok(s))) => {
+                    debug!("connect {} via {}", target, addr);
+                    return Ok(s);
+                }
+                Some((addr, Err(err))) => {
+                    debug!("connect {} {} {:?}", target, addr, err);
+                    last = Some(err);
+                    // no need to wait for the delay
+                    true
+                }
+            };
+        }
+    }
+
+    async fn client_handshake(ctx: Arc<Context>, client: TcpStream, ip: IpAddr) {
+        let local = client.local_addr();
+        let (r0, w0) = client.into_split();
 
-        let port = (&port_buf[..]).get_u16();
+        let mut client_en = Encryption::new(ctx.key.clone(), w0);
+        let mut client_de = Decryption::new(ctx.key.clone(), r0);
 
-        match std::str::from_utf8(&host[..]) {
-            Err(err) => io::Result::Err(io::Error::new(io::ErrorKind::InvalidData, err)),
-            Ok(host) => {
-                let addr = format!("{}:{}", host, port);
-                info!("connect {:?}", &addr);
-                TcpStream::connect(&addr).await
+        // step 1: the first frame carries the target, followed by early data
+        let request = match timeout(HANDSHAKE_TIMEOUT, client_de.decryption_read()).await {
+            Err(_) => {
+                warn!("client_handshake step 1-1 {} timed out", ip);
+                ctx.client_failed(ip);
+                return;
+            }
+            Ok(Err(err)) => {
+                warn!("client_handshake step 1-1 {} {:?}", ip, err);
+                ctx.client_failed(ip);
+                return;
+            }
+            Ok(Ok(request)) => request,
+        };
+        let (header, n) = match Request::from_bytes(&request) {
+            Err(err) => {
+                warn!("client_handshake step 1-2 {} {:?}", ip, err);
+                ctx.client_failed(ip);
+                return;
+            }
+            Ok(header) => header,
+        };
+
+        match header.cmd {
+            socks5::CMD_CONNECT => {
+                Self::client_connect(&ctx, client_en, client_de, header, &request[n..]).await
+            }
+            socks5::CMD_BIND => match local {
+                Err(err) => warn!("client_handshake step 1-3 {:?}", err),
+                Ok(local) => Self::client_bind(client_en, client_de, header, local).await,
+            },
+            socks5::CMD_UDP_ASSOCIATE => {
+                Self::client_udp_associate(&ctx, client_en, client_de, header).await
+            }
+            CMD_RESOLVE => Self::client_resolve(&ctx, client_en, header).await,
+            CMD_REVERSE => {
+                Self::client_reverse(&ctx, client_en, client_de, header, &request[n..]).await
+            }
+            CMD_REVERSE_ACCEPT => {
+                Self::client_reverse_accept(&ctx, client_en, client_de, header, &request[n..]).await
+            }
+            cmd => {
+                warn!("client_handshake step 1-4 {:#04x}", cmd);
+                let reply = Reply::new(socks5::REP_COMMAND_NOT_SUPPORTED, Address::unspecified());
+                if let Err(err) = client_en.encryption_write(&reply.to_bytes()).await {
+                    debug!("client_en.encryption_write {:?}", err);
+                }
             }
         }
     }
 
-    async fn socks5_ipv4_connect(client_de: &mut Decryption) -> io::Result<TcpStream> {
-        // ip
-        let mut ip_buf = [0_u8; 6];
-        client_de.decryption_read_exact(&mut ip_buf).await?;
+    async fn client_connect(
+        ctx: &Context,
+        mut client_en: Encryption,
+        client_de: Decryption,
+        header: Request,
+        early_data: &[u8],
+    ) {
+        // step 2
+        let s1 = match Self::connect(ctx, &header.target, header.user_name()).await {
+            Err(err) => {
+                warn!(
+                    "client_connect step 2 {} user {} {:?}",
+                    header.target,
+                    header.user_name(),
+                    err
+                );
+                let reply = Reply::new(socks5::reply_code(&err), Address::unspecified());
+                if let Err(err) = client_en.encryption_write(&reply.to_bytes()).await {
+                    debug!("client_en.encryption_write {:?}", err);
+                }
+                return;
+            }
+            Ok(s) => s,
+        };
 
-        let port = (&ip_buf[4..]).get_u16();
+        // step 3
+        let bind = match s1.local_addr() {
+            Err(err) => {
+                debug!("s1.local_addr {:?}", err);
+                Address::unspecified()
+            }
+            Ok(addr) => Address::Ip(addr),
+        };
+        info!(
+            "connect {} user {} from {}",
+            header.target,
+            header.user_name(),
+            bind
+        );
+        let reply = Reply::new(socks5::REP_SUCCEEDED, bind);
+        if let Err(err) = client_en.encryption_write(&reply.to_bytes()).await {
+            warn!("client_connect step 3 {:?}", err);
+            return;
+        }
+
+        let (r1, mut w1) = s1.into_split();
+
+        // step 4
+        if !early_data.is_empty() {
+            if let Err(err) = w1.write_all(early_data).await {
+                warn!("client_connect step 4 {:?}", err);
+                return;
+            }
+        }
 
-        let ip_addr = Ipv4Addr::new(ip_buf[0], ip_buf[1], ip_buf[2], ip_buf[3]);
-        let addr = SocketAddr::V4(SocketAddrV4::new(ip_addr, port));
-        info!("connect {:?}", &addr);
-        TcpStream::connect(addr).await
+        spawn(Self::proc0(client_de, w1));
+        spawn(Self::proc1(client_en, r1));
     }
 
-    async fn socks5_ipv6_connect(client_de: &mut Decryption) -> io::Result<TcpStream> {
-        // ip
-        let mut ip_buf = [0_u8; 16];
-        client_de.decryption_read_exact(&mut ip_buf).await?;
+    /// Listens on the address the local reached us at and hands the first
+    /// matching incoming connection to the client. When the request names an
+    /// IP address, only connections from that address are accepted.
+    async fn client_bind(
+        mut client_en: Encryption,
+        client_de: Decryption,
+        header: Request,
+        local: SocketAddr,
+    ) {
+        info!("bind {} user {}", header.target, header.user_name());
 
-        let mut port_buf = [0_u8; 2];
-        client_de.decryption_read_exact(&mut port_buf).await?;
+        // step 2
+        let mut listener = match TcpListener::bind(SocketAddr::new(local.ip(), 0)).await {
+            Err(err) => {
+                warn!("client_bind step 2 {:?}", err);
+                let reply = Reply::new(socks5::reply_code(&err), Address::unspecified());
+                if let Err(err) = client_en.encryption_write(&reply.to_bytes()).await {
+                    debug!("client_en.encryption_write {:?}", err);
+                }
+                return;
+            }
+            Ok(listener) => listener,
+        };
+        let bind = match listener.local_addr() {
+            Err(err) => {
+                warn!("client_bind step 2 {:?}", err);
+                return;
+            }
+            Ok(addr) => addr,
+        };
 
-        let ip = Ipv6Addr::from(ip_buf);
-        let port = (&port_buf[..]).get_u16();
+        // step 3
+        let reply = Reply::new(socks5::REP_SUCCEEDED, Address::Ip(bind));
+        if let Err(err) = client_en.encryption_write(&reply.to_bytes()).await {
+            warn!("client_bind step 3 {:?}", err);
+            return;
+        }
 
-        let addr = SocketAddr::V6(SocketAddrV6::new(ip, port, 0, 0));
-        info!("connect {:?}", &addr);
-        TcpStream::connect(addr).await
+        // step 4
+        let expected = match header.target {
+            Address::Ip(addr) if !addr.ip().is_unspecified() => Some(addr.ip()),
+            _ => None,
+        };
+        let accept = async {
+            loop {
+                let (s1, peer) = listener.accept().await?;
+                match expected {
+                    Some(ip) if ip != peer.ip() => {
+                        warn!("client_bind step 4 unexpected peer {}", peer);
+                    }
+                    _ => return io::Result::Ok((s1, peer)),
+                }
+            }
+        };
+        let (s1, peer) = match timeout(BIND_TIMEOUT, accept).await {
+            Err(_) => {
+                warn!("client_bind step 4 {} timed out", bind);
+                let reply = Reply::new(socks5::REP_TTL_EXPIRED, Address::unspecified());
+                if let Err(err) = client_en.encryption_write(&reply.to_bytes()).await {
+                    debug!("client_en.encryption_write {:?}", err);
+                }
+                return;
+            }
+            Ok(Err(err)) => {
+                warn!("client_bind step 4 {:?}", err);
+                let reply = Reply::new(socks5::REP_GENERAL_FAILURE, Address::unspecified());
+                if let Err(err) = client_en.encryption_write(&reply.to_bytes()).await {
+                    debug!("client_en.encryption_write {:?}", err);
+                }
+                return;
+            }
+            Ok(Ok(accepted)) => accepted,
+        };
+        drop(listener);
+
+        // step 5
+        info!("bind {} accepted {}", bind, peer);
+        let reply = Reply::new(socks5::REP_SUCCEEDED, Address::Ip(peer));
+        if let Err(err) = client_en.encryption_write(&reply.to_bytes()).await {
+            warn!("client_bind step 5 {:?}", err);
+            return;
+        }
+
+        let (r1, w1) = s1.into_split();
+        spawn(Self::proc0(client_de, w1));
+        spawn(Self::proc1(client_en, r1));
     }
 
-    async fn client_socks5_handshake(key: String, client: TcpStream) {
-        let (r0, w0) = client.into_split();
+    /// Resolves a domain for the local's DNS forwarder.
+    async fn client_resolve(ctx: &Context, mut client_en: Encryption, header: Request) {
+        // step 2
+        debug!("resolve {} user {}", header.target, header.user_name());
+        let host = match &header.target {
+            Address::Ip(addr) => addr.ip().to_string(),
+            Address::Domain(host, _) => host.clone(),
+        };
+        let (ips, ttl) = match ctx.resolver.lookup(&host).await {
+            Err(err) => {
+                debug!("client_resolve step 2 {} {:?}", header.target, err);
+                // only a name without addresses reads as NXDOMAIN to the local
+                let rep = match err.kind() {
+                    io::ErrorKind::NotFound => socks5::REP_HOST_UNREACHABLE,
+                    _ => socks5::REP_GENERAL_FAILURE,
+                };
+                let reply = Reply::new(rep, Address::unspecified());
+                if let Err(err) = client_en.encryption_write(&reply.to_bytes()).await {
+                    debug!("client_en.encryption_write {:?}", err);
+                }
+                return;
+            }
+            Ok(found) => found,
+        };
 
-        let mut client_en = Encryption::new(key.clone(), w0);
-        let mut client_de = Decryption::new(key.clone(), r0);
+        // step 3
+        let reply = Reply::new(socks5::REP_SUCCEEDED, Address::unspecified());
+        let mut answer = ttl.to_be_bytes().to_vec();
+        for ip in ips {
+            answer.extend_from_slice(&Address::Ip(SocketAddr::new(ip, 0)).to_bytes());
+        }
+        for frame in &[reply.to_bytes(), answer] {
+            if let Err(err) = client_en.encryption_write(frame).await {
+                warn!("client_resolve step 3 {:?}", err);
+                return;
+            }
+        }
+    }
 
-        let mut data = [0_u8; 3];
-        // step 1
-        match client_de.decryption_read_exact(&mut data[..]).await {
+    /// Serves a reverse tunnel control connection: listens on the requested
+    /// address, if the user may, and announces each accepted connection to
+    /// the local, which dials back for it.
+    async fn client_reverse(
+        ctx: &Arc<Context>,
+        mut client_en: Encryption,
+        client_de: Decryption,
+        header: Request,
+        password: &[u8],
+    ) {
+        info!("reverse {} user {}", header.target, header.user_name());
+
+        // step 2
+        // PLEN PASSWORD
+        let password = password
+            .split_first()
+            .and_then(|(&len, rest)| rest.get(..len as usize))
+            .unwrap_or(&[]);
+        let rep = match header.target {
+            Address::Ip(listen)
+                if ctx.reverse_allowed(header.user.as_deref(), password, listen.port()) =>
+            {
+                match TcpListener::bind(listen).await {
+                    Err(err) => Err((socks5::reply_code(&err), err)),
+                    Ok(listener) => Ok(listener),
+                }
+            }
+            _ => Err((
+                socks5::REP_CONNECTION_NOT_ALLOWED,
+                io::Error::new(io::ErrorKind::PermissionDenied, "not allowed"),
+            )),
+        };
+        let mut listener = match rep {
+            Err((rep, err)) => {
+                warn!(
+                    "client_reverse step 2 {} user {} {:?}",
+                    header.target,
+                    header.user_name(),
+                    err
+                );
+                let reply = Reply::new(rep, Address::unspecified());
+                if let Err(err) = client_en.encryption_write(&reply.to_bytes()).await {
+                    debug!("client_en.encryption_write {:?}", err);
+                }
+                return;
+            }
+            Ok(listener) => listener,
+        };
+        let bind = match listener.local_addr() {
             Err(err) => {
-                warn!("client_socks5_handshake step 1-1 {:?}", err);
+                warn!("client_reverse step 2 {:?}", err);
                 return;
             }
-            Ok(read_n) => {
-                if read_n != 3 {
-                    warn!("client_socks5_handshake step 1-2 {:?}", data);
-                    return;
-                } else if data[0] != 0x05 || data[1] != 0x01 || data[2] != 0x00 {
-                    warn!("client_socks5_handshake step 1-3 {:?}", data);
+            Ok(addr) => addr,
+        };
+
+        // step 3
+        let reply = Reply::new(socks5::REP_SUCCEEDED, Address::Ip(bind));
+        if let Err(err) = client_en.encryption_write(&reply.to_bytes()).await {
+            warn!("client_reverse step 3 {:?}", err);
+            return;
+        }
+
+        // step 4
+        let mut frames = client_de.into_frames();
+        loop {
+            select! {
+                accepted = listener.accept() => {
+                    let (s1, peer) = match accepted {
+                        Err(err) => {
+                            warn!("client_reverse step 4 {:?}", err);
+                            continue;
+                        }
+                        Ok(accepted) => accepted,
+                    };
+                    let mut id = [0_u8; 8];
+                    if let Err(err) = rand_bytes(&mut id) {
+                        warn!("client_reverse step 4 {:?}", err);
+                        continue;
+                    }
+                    let (tx, rx) = oneshot::channel();
+                    if let Ok(mut pending) = ctx.pending.lock() {
+                        pending.insert(u64::from_be_bytes(id), tx);
+                    }
+                    info!("reverse {} accepted {}", bind, peer);
+                    let mut notice = id.to_vec();
+                    notice.extend_from_slice(&Address::Ip(peer).to_bytes());
+                    if let Err(err) = client_en.encryption_write(&notice).await {
+                        warn!("client_reverse step 4 {:?}", err);
+                        return;
+                    }
+                    spawn(Self::reverse_wait(ctx.clone(), u64::from_be_bytes(id), rx, s1));
+                }
+                data = timeout(REVERSE_IDLE_TIMEOUT, frames.recv()) => {
+                    match data {
+                        Err(_) => warn!("client_reverse step 4 {} timed out", bind),
+                        Ok(None) => {}
+                        Ok(Some(Err(err))) => debug!("client_de.decryption_read {:?}", err),
+                        Ok(Some(Ok(_))) => {
+                            if let Err(err) = client_en.encryption_write(&[]).await {
+                                debug!("client_en.encryption_write {:?}", err);
+                                return;
+                            }
+                            continue;
+                        }
+                    }
+                    info!("reverse {} closed", bind);
                     return;
                 }
             }
         }
+    }
 
+    /// Hands an accepted reverse tunnel connection over to the tunnel the
+    /// local dials back with.
+    async fn reverse_wait(
+        ctx: Arc<Context>,
+        id: u64,
+        rx: oneshot::Receiver<(Encryption, Decryption)>,
+        s1: TcpStream,
+    ) {
+        let (client_en, client_de) = match timeout(REVERSE_ACCEPT_TIMEOUT, rx).await {
+            Ok(Ok(tunnel)) => tunnel,
+            _ => {
+                warn!("reverse_wait {:016x} no dial back", id);
+                if let Ok(mut pending) = ctx.pending.lock() {
+                    pending.remove(&id);
+                }
+                return;
+            }
+        };
+        let (r1, w1) = s1.into_split();
+        spawn(Self::proc0(client_de, w1));
+        spawn(Self::proc1(client_en, r1));
+    }
+
+    async fn client_reverse_accept(
+        ctx: &Arc<Context>,
+        mut client_en: Encryption,
+        client_de: Decryption,
+        header: Request,
+        id: &[u8],
+    ) {
         // step 2
-        if let Err(err) = client_en.encryption_write(&[0x05_u8, 0x00_u8]).await {
-            warn!("client_socks5_handshake step 2-1 {:?}", err);
+        let tx = match id.get(..8) {
+            None => None,
+            Some(bytes) => {
+                let mut id = [0_u8; 8];
+                id.copy_from_slice(bytes);
+                let id = u64::from_be_bytes(id);
+                ctx.pending
+                    .lock()
+                    .ok()
+                    .and_then(|mut pending| pending.remove(&id))
+            }
+        };
+        let tx = match tx {
+            None => {
+                warn!(
+                    "client_reverse_accept step 2 {} user {} unknown ID",
+                    header.target,
+                    header.user_name()
+                );
+                let reply = Reply::new(socks5::REP_GENERAL_FAILURE, Address::unspecified());
+                if let Err(err) = client_en.encryption_write(&reply.to_bytes()).await {
+                    debug!("client_en.encryption_write {:?}", err);
+                }
+                return;
+            }
+            Some(tx) => tx,
+        };
+
+        // step 3
+        let reply = Reply::new(socks5::REP_SUCCEEDED, Address::unspecified());
+        if let Err(err) = client_en.encryption_write(&reply.to_bytes()).await {
+            warn!("client_reverse_accept step 3 {:?}", err);
             return;
         }
+        if tx.send((client_en, client_de)).is_err() {
+            debug!("client_reverse_accept step 3 {} gave up", header.target);
+        }
+    }
 
-        let mut data = [0_u8; 4];
+    async fn udp_recv(
+        recv: &mut Option<RecvHalf>,
+        buf: &mut [u8],
+    ) -> io::Result<(usize, SocketAddr)> {
+        match recv {
+            Some(recv) => recv.recv_from(buf).await,
+            None => future::pending().await,
+        }
+    }
 
-        // step 3
-        match client_de.decryption_read_exact(&mut data).await {
+    /// Relays a UDP association. Every frame carries one datagram,
+    /// `ATYP ADDR PORT DATA`, with the destination on the way out and the
+    /// source on the way back. Replies are only let through from
+    /// destinations in the NAT table, whose entries expire when idle.
+    /// Domain destinations are resolved in tasks of their own, so that a
+    /// slow lookup holds up no other destination.
+    async fn client_udp_associate(
+        ctx: &Arc<Context>,
+        mut client_en: Encryption,
+        client_de: Decryption,
+        header: Request,
+    ) {
+        // step 2
+        let outbound = ctx.outbound(header.user_name());
+        let source_v4 = outbound.source(false, None, header.user_name());
+        let source_v6 = outbound.source(true, None, header.user_name());
+        info!(
+            "udp associate user {} from {:?} {:?}",
+            header.user_name(),
+            source_v4,
+            source_v6
+        );
+        let (mut recv_v4, mut send_v4) = match outbound.udp_bind(false, source_v4) {
             Err(err) => {
-                warn!("client_socks5_handshake step 3-1 {:?}", err);
+                warn!("client_udp_associate step 2 {:?}", err);
+                let reply = Reply::new(socks5::reply_code(&err), Address::unspecified());
+                if let Err(err) = client_en.encryption_write(&reply.to_bytes()).await {
+                    debug!("client_en.encryption_write {:?}", err);
+                }
                 return;
             }
-            Ok(read_n) => {
-                if read_n != 4 {
-                    warn!("client_socks5_handshake step 3-2 {:?}", data);
-                    return;
-                } else if data[0] != 0x05 || data[1] != 0x01 || data[2] != 0x00 {
-                    warn!("client_socks5_handshake step 3-3 {:?}", data);
-                    return;
-                }
+            Ok(socket) => {
+                let (recv, send) = socket.split();
+                (Some(recv), Some(send))
             }
-        }
+        };
+        let (mut recv_v6, mut send_v6) = match outbound.udp_bind(true, source_v6) {
+            Err(err) => {
+                debug!("client_udp_associate step 2 {:?}", err);
+                (None, None)
+            }
+            Ok(socket) => {
+                let (recv, send) = socket.split();
+                (Some(recv), Some(send))
+            }
+        };
 
-        let s1: TcpStream;
+        // step 3
+        let reply = Reply::new(socks5::REP_SUCCEEDED, Address::unspecified());
+        if let Err(err) = client_en.encryption_write(&reply.to_bytes()).await {
+            warn!("client_udp_associate step 3 {:?}", err);
+            return;
+        }
 
-        if data[3] == 0x03 {
-            s1 = match Self::socks5_host_connect(&mut client_de).await {
-                Err(err) => {
-                    warn!("client_socks5_handshake step 3-4 {:?}", err);
-                    return;
+        // step 4
+        let mut frames = client_de.into_frames();
+        let (resolved_tx, mut resolved_rx) = mpsc::channel(16);
+        // datagrams waiting for their destination to be resolved
+        let mut resolving: HashMap<Address, Vec<Vec<u8>>> = HashMap::new();
+        let mut nat: HashMap<SocketAddr, Instant> = HashMap::new();
+        let mut expire = interval(UDP_IDLE_TIMEOUT);
+        let mut buffer_v4 = vec![0_u8; 65536];
+        let mut buffer_v6 = vec![0_u8; 65536];
+        loop {
+            let (datagram, src) = select! {
+                data = frames.recv() => {
+                    let data = match data {
+                        None => return,
+                        Some(Err(err)) => {
+                            debug!("client_de.decryption_read {:?}", err);
+                            return;
+                        }
+                        Some(Ok(data)) => data,
+                    };
+                    let (target, n) = match Address::from_bytes(&data) {
+                        Err(err) => {
+                            debug!("client_udp_associate step 4-1 {:?}", err);
+                            continue;
+                        }
+                        Ok(target) => target,
+                    };
+                    if let Address::Ip(_) = target {
+                        let addrs = Self::resolve(ctx, &target).await;
+                        let sockets = (&mut send_v4, &mut send_v6);
+                        let datagrams = [&data[n..]];
+                        Self::udp_send(sockets, &mut nat, &header, &target, addrs, &datagrams)
+                            .await;
+                        continue;
+                    }
+                    if let Some(waiting) = resolving.get_mut(&target) {
+                        if waiting.len() < UDP_RESOLVE_QUEUE {
+                            waiting.push(data[n..].to_vec());
+                        }
+                        continue;
+                    }
+                    resolving.insert(target.clone(), vec![data[n..].to_vec()]);
+                    let ctx = ctx.clone();
+                    let mut resolved_tx = resolved_tx.clone();
+                    spawn(async move {
+                        let addrs = Self::resolve(&ctx, &target).await;
+                        let _ = resolved_tx.send((target, addrs)).await;
+                    });
+                    continue;
                 }
-                Ok(s) => s,
-            };
-        } else if data[3] == 0x01 {
-            s1 = match Self::socks5_ipv4_connect(&mut client_de).await {
-                Err(err) => {
-                    warn!("client_socks5_handshake step 3-5 {:?}", err);
-                    return;
+                resolved = resolved_rx.recv() => {
+                    if let Some((target, addrs)) = resolved {
+                        let waiting = resolving.remove(&target).unwrap_or_default();
+                        let datagrams: Vec<&[u8]> = waiting.iter().map(|data| &data[..]).collect();
+                        let sockets = (&mut send_v4, &mut send_v6);
+                        Self::udp_send(sockets, &mut nat, &header, &target, addrs, &datagrams)
+                            .await;
+                    }
+                    continue;
                 }
-                Ok(s) => s,
-            };
-        } else if data[3] == 0x04 {
-            s1 = match Self::socks5_ipv6_connect(&mut client_de).await {
-                Err(err) => {
-                    warn!("client_socks5_handshake step 3-5 {:?}", err);
-                    return;
+                recv = Self::udp_recv(&mut recv_v4, &mut buffer_v4) => match recv {
+                    Err(err) => {
+                        debug!("recv_v4.recv_from {:?}", err);
+                        return;
+                    }
+                    Ok((n, src)) => (&buffer_v4[..n], src),
+                },
+                recv = Self::udp_recv(&mut recv_v6, &mut buffer_v6) => match recv {
+                    Err(err) => {
+                        debug!("recv_v6.recv_from {:?}", err);
+                        return;
+                    }
+                    Ok((n, src)) => (&buffer_v6[..n], src),
+                },
+                _ = expire.tick() => {
+                    nat.retain(|_, last| last.elapsed() < UDP_IDLE_TIMEOUT);
+                    continue;
                 }
-                Ok(s) => s,
             };
-        } else {
-            warn!("client_socks5_handshake step 3-4");
-            return;
-        }
 
-        // step 4
-        let resp: [u8; 10] = [0x05, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
-        if let Err(err) = client_en.encryption_write(&resp).await {
-            warn!("client_socks5_handshake step 4 {:?}", err);
-            return;
+            match nat.get_mut(&src) {
+                Some(last) if last.elapsed() < UDP_IDLE_TIMEOUT => *last = Instant::now(),
+                _ => {
+                    debug!("client_udp_associate drop datagram from {}", src);
+                    continue;
+                }
+            }
+            let mut frame = Address::Ip(src).to_bytes();
+            frame.extend_from_slice(datagram);
+            if let Err(err) = client_en.encryption_write(&frame).await {
+                debug!("client_en.encryption_write {:?}", err);
+                return;
+            }
         }
+    }
 
-        let (r1, w1) = s1.into_split();
-        spawn(Self::proc0(client_de, w1));
-        spawn(Self::proc1(client_en, r1));
+    /// Sends datagrams of a UDP association to the first address of their
+    /// destination that a socket of its family is open for, and lets replies
+    /// from it through.
+    async fn udp_send(
+        (send_v4, send_v6): (&mut Option<SendHalf>, &mut Option<SendHalf>),
+        nat: &mut HashMap<SocketAddr, Instant>,
+        header: &Request,
+        target: &Address,
+        addrs: io::Result<Vec<SocketAddr>>,
+        datagrams: &[&[u8]],
+    ) {
+        let addrs = match addrs {
+            Err(err) => {
+                debug!("client_udp_associate step 4-2 {} {:?}", target, err);
+                return;
+            }
+            Ok(addrs) => addrs,
+        };
+        let addr = match addrs.iter().find(|addr| match addr {
+            SocketAddr::V4(_) => send_v4.is_some(),
+            SocketAddr::V6(_) => send_v6.is_some(),
+        }) {
+            None => {
+                debug!("client_udp_associate step 4-3 {} {:?}", target, addrs);
+                return;
+            }
+            Some(addr) => addr,
+        };
+        let send = match addr {
+            SocketAddr::V4(_) => send_v4.as_mut(),
+            SocketAddr::V6(_) => send_v6.as_mut(),
+        };
+        if nat.insert(*addr, Instant::now()).is_none() {
+            info!("udp {} user {}", target, header.user_name());
+        }
+        if let Some(send) = send {
+            for datagram in datagrams {
+                if let Err(err) = send.send_to(datagram, addr).await {
+                    debug!("send.send_to {} {:?}", addr, err);
+                }
+            }
+        }
     }
 
     async fn proc0(mut client_de: Decryption, mut target_writer: OwnedWriteHalf) {
@@ -203,16 +933,13 @@ impl RemoteServer {
                     return;
                 }
                 Ok(n) => {
-                    if n <= 0 {
+                    if n == 0 {
                         debug!("target_reader.read eof");
                         return;
                     }
-                    match client_en.encryption_write(&buffer[..n]).await {
-                        Err(err) => {
-                            debug!("client_en.encryption_write {:?}", err);
-                            return;
-                        }
-                        _ => {}
+                    if let Err(err) = client_en.encryption_write(&buffer[..n]).await {
+                        debug!("client_en.encryption_write {:?}", err);
+                        return;
                     }
                 }
             }
@@ -222,9 +949,59 @@ impl RemoteServer {
     async fn run(&mut self) -> Result<(), Box<dyn Error>> {
         let mut listenner = TcpListener::bind(&self.listen).await?;
         loop {
-            let (client, _) = listenner.accept().await?;
-            let key = self.key.clone();
-            spawn(Self::client_socks5_handshake(key, client));
+            let (client, peer) = listenner.accept().await?;
+            let ip = acl::unmap(&peer.ip());
+            if !self.context.client_allowed(&ip) {
+                debug!("client {} refused", peer);
+                continue;
+            }
+            spawn(Self::client_handshake(self.context.clone(), client, ip));
+        }
+    }
+}
+
+/// Reorders addresses so that the families alternate, starting with the
+/// family of the first, otherwise keeping their order.
+fn interleave(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let first_v4 = match addrs.first() {
+        None => return addrs,
+        Some(addr) => addr.is_ipv4(),
+    };
+    let (first, second): (Vec<SocketAddr>, Vec<SocketAddr>) = addrs
+        .into_iter()
+        .partition(|addr| addr.is_ipv4() == first_v4);
+    let mut second = second.into_iter();
+    let mut interleaved = vec![];
+    for addr in first {
+        interleaved.push(addr);
+        interleaved.extend(second.next());
+    }
+    interleaved.extend(second);
+    interleaved
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addrs(addrs: &[&str]) -> Vec<SocketAddr> {
+        addrs.iter().map(|addr| addr.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn interleave_families() {
+        let (a, b, c) = ("192.0.2.1:80", "192.0.2.2:80", "192.0.2.3:80");
+        let (x, y) = ("[2001:db8::1]:80", "[2001:db8::2]:80");
+        let cases: &[(&[&str], &[&str])] = &[
+            (&[], &[]),
+            (&[a], &[a]),
+            (&[a, b, c], &[a, b, c]),
+            (&[a, b, x, y], &[a, x, b, y]),
+            (&[x, a, b, c, y], &[x, a, y, b, c]),
+            (&[a, x, y], &[a, x, y]),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(interleave(addrs(input)), addrs(expected), "{:?}", input);
         }
     }
 }
@@ -0,0 +1,97 @@
+use crate::address::Address;
+use bytes::BufMut;
+use std::io;
+use std::io::{Error, ErrorKind};
+
+/// Opens a reverse tunnel: the remote listens on the target address and
+/// announces each connection it accepts with a frame `ID ATYP ADDR PORT`,
+/// `ID` being 8 bytes. Empty frames are keepalives, echoed by the remote.
+/// The header is followed by the user's password, `PLEN PASSWORD`.
+pub const CMD_REVERSE: u8 = 0x80;
+
+/// Dials back for a reverse tunnel connection, the header followed by its
+/// `ID`.
+pub const CMD_REVERSE_ACCEPT: u8 = 0x81;
+
+/// Resolves the target domain on the remote. A successful reply is followed
+/// by a frame `TTL ATYP ADDR PORT ...`, `TTL` being 4 bytes, listing the
+/// addresses.
+pub const CMD_RESOLVE: u8 = 0x82;
+
+/// Header of the first frame on a tunnel connection, followed by early data:
+/// `CMD ULEN USER ATYP DST.ADDR DST.PORT`, `CMD` being a SOCKS5 command.
+/// `ULEN` is 0 for anonymous clients.
+#[derive(Clone, Debug)]
+pub struct Request {
+    pub cmd: u8,
+    pub user: Option<String>,
+    pub target: Address,
+}
+
+impl Request {
+    pub fn new(cmd: u8, user: Option<String>, target: Address) -> Request {
+        Request { cmd, user, target }
+    }
+
+    pub fn user_name(&self) -> &str {
+        self.user.as_deref().unwrap_or("-")
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buffer = vec![self.cmd];
+        match &self.user {
+            None => buffer.put_u8(0),
+            Some(user) => {
+                buffer.put_u8(user.len() as u8);
+                buffer.put_slice(user.as_bytes());
+            }
+        }
+        buffer.put_slice(&self.target.to_bytes());
+        buffer
+    }
+
+    /// Parses a request from the front of `buf`, returning it together with
+    /// the number of bytes consumed.
+    pub fn from_bytes(buf: &[u8]) -> io::Result<(Request, usize)> {
+        let short = || Error::new(ErrorKind::UnexpectedEof, "request truncated");
+        let cmd = *buf.first().ok_or_else(short)?;
+        let user_len = *buf.get(1).ok_or_else(short)? as usize;
+        let user = match user_len {
+            0 => None,
+            _ => match std::str::from_utf8(buf.get(2..2 + user_len).ok_or_else(short)?) {
+                Err(err) => return Err(Error::new(ErrorKind::InvalidData, err)),
+                Ok(user) => Some(user.to_string()),
+            },
+        };
+        let offset = 2 + user_len;
+        let (target, n) = Address::from_bytes(&buf[offset..])?;
+        Ok((Request { cmd, user, target }, offset + n))
+    }
+}
+
+/// First frame the remote sends back: `REP ATYP BND.ADDR BND.PORT`, using the
+/// SOCKS5 reply codes.
+#[derive(Clone, Debug)]
+pub struct Reply {
+    pub rep: u8,
+    pub bind: Address,
+}
+
+impl Reply {
+    pub fn new(rep: u8, bind: Address) -> Reply {
+        Reply { rep, bind }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buffer = vec![self.rep];
+        buffer.put_slice(&self.bind.to_bytes());
+        buffer
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> io::Result<(Reply, usize)> {
+        let short = || Error::new(ErrorKind::UnexpectedEof, "reply truncated");
+        let rep = *buf.first().ok_or_else(short)?;
+        let (bind, n) = Address::from_bytes(&buf[1..])?;
+        Ok((Reply { rep, bind }, 1 + n))
+    }
+}
@@ -0,0 +1,547 @@
+use crate::dns;
+use openssl::rand::rand_bytes;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::io::{Error, ErrorKind};
+use std::iter;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::join;
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::prelude::*;
+use tokio::time::timeout;
+
+pub const HOSTS_FILE: &str = "/etc/hosts";
+const RESOLV_CONF: &str = "/etc/resolv.conf";
+
+// TTL given to addresses from the hosts file, or given as IP literals.
+const HOSTS_TTL: u32 = 60;
+
+// Largest UDP response asked of the upstreams, the usual EDNS default that
+// avoids fragmentation.
+const UDP_PAYLOAD_SIZE: u16 = 1232;
+
+/// Which address families to look up, and which to try first.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Prefer {
+    Ipv4,
+    Ipv6,
+    Ipv4Only,
+    Ipv6Only,
+}
+
+impl Prefer {
+    pub fn parse(s: &str) -> Option<Prefer> {
+        match s {
+            "ipv4" => Some(Prefer::Ipv4),
+            "ipv6" => Some(Prefer::Ipv6),
+            "ipv4-only" => Some(Prefer::Ipv4Only),
+            "ipv6-only" => Some(Prefer::Ipv6Only),
+            _ => None,
+        }
+    }
+}
+
+/// Asynchronous stub resolver: looks names up in the hosts file, then asks
+/// the upstream servers in turn, caching their responses, negative ones
+/// included, for as long as their TTLs allow.
+pub struct Resolver {
+    upstreams: Vec<SocketAddr>,
+    hosts: HashMap<String, Vec<IpAddr>>,
+    // per query to each upstream
+    timeout: Duration,
+    prefer: Prefer,
+    cache: Mutex<dns::Cache>,
+}
+
+impl Resolver {
+    /// With no `upstreams`, uses the name servers of `/etc/resolv.conf`.
+    pub fn new(
+        upstreams: Vec<SocketAddr>,
+        hosts_file: &str,
+        timeout: Duration,
+        prefer: Prefer,
+    ) -> io::Result<Resolver> {
+        let upstreams = if upstreams.is_empty() {
+            system_upstreams()
+        } else {
+            upstreams
+        };
+        if upstreams.is_empty() {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                format!("no name server in {}", RESOLV_CONF),
+            ));
+        }
+        let hosts = if hosts_file.is_empty() {
+            HashMap::new()
+        } else {
+            match fs::read_to_string(hosts_file) {
+                Err(err) => {
+                    warn!("read {} {:?}", hosts_file, err);
+                    HashMap::new()
+                }
+                Ok(text) => parse_hosts(&text),
+            }
+        };
+        info!("resolver upstreams {:?}, {} hosts", upstreams, hosts.len());
+        Ok(Resolver {
+            upstreams,
+            hosts,
+            timeout,
+            prefer,
+            cache: Mutex::new(dns::Cache::default()),
+        })
+    }
+
+    /// Looks up the addresses of `host`, in order of preference, along with
+    /// how long they may be kept.
+    pub async fn lookup(&self, host: &str) -> io::Result<(Vec<IpAddr>, u32)> {
+        if let Ok(ip) = host.trim_start_matches('[').trim_end_matches(']').parse() {
+            return Ok((vec![ip], HOSTS_TTL));
+        }
+        let name = host.trim_end_matches('.').to_ascii_lowercase();
+        if let Some(addrs) = self.hosts.get(&name) {
+            let addrs = self.order(addrs.clone());
+            if !addrs.is_empty() {
+                return Ok((addrs, HOSTS_TTL));
+            }
+        }
+
+        let (v4, v6) = match self.prefer {
+            Prefer::Ipv4Only => (self.query(&name, dns::TYPE_A).await, Ok((vec![], 0))),
+            Prefer::Ipv6Only => (Ok((vec![], 0)), self.query(&name, dns::TYPE_AAAA).await),
+            _ => join!(
+                self.query(&name, dns::TYPE_A),
+                self.query(&name, dns::TYPE_AAAA)
+            ),
+        };
+        let mut found = vec![];
+        let mut ttl = u32::MAX;
+        let mut error = None;
+        for result in iter::once(v4).chain(iter::once(v6)) {
+            match result {
+                Err(err) => error = Some(err),
+                Ok((addrs, _)) if addrs.is_empty() => {}
+                Ok((mut addrs, addrs_ttl)) => {
+                    ttl = ttl.min(addrs_ttl);
+                    found.append(&mut addrs);
+                }
+            }
+        }
+        if found.is_empty() {
+            return Err(error.unwrap_or_else(|| {
+                Error::new(ErrorKind::NotFound, format!("no address for {}", host))
+            }));
+        }
+        Ok((self.order(found), ttl))
+    }
+
+    /// Keeps the allowed families, the preferred one first.
+    fn order(&self, addrs: Vec<IpAddr>) -> Vec<IpAddr> {
+        let (v4, v6): (Vec<IpAddr>, Vec<IpAddr>) = addrs.into_iter().partition(|ip| ip.is_ipv4());
+        match self.prefer {
+            Prefer::Ipv4 => v4.into_iter().chain(v6).collect(),
+            Prefer::Ipv6 => v6.into_iter().chain(v4).collect(),
+            Prefer::Ipv4Only => v4,
+            Prefer::Ipv6Only => v6,
+        }
+    }
+
+    /// Addresses of one type for `name`, from the cache or the upstreams,
+    /// with their TTL. A name without such addresses gives an empty list.
+    async fn query(&self, name: &str, qtype: u16) -> io::Result<(Vec<IpAddr>, u32)> {
+        let mut id = [0_u8; 2];
+        rand_bytes(&mut id)?;
+        let query = dns::query(u16::from_be_bytes(id), name, qtype, UDP_PAYLOAD_SIZE)?;
+        let (question, _) = dns::Question::parse(&query)?;
+
+        let cached = match self.cache.lock() {
+            Err(_) => None,
+            Ok(mut cache) => cache.get(&question, dns::id(&query)),
+        };
+        let response = match cached {
+            Some(response) => response,
+            None => {
+                let response = self.exchange(&question, &query).await?;
+                if let Ok(mut cache) = self.cache.lock() {
+                    cache.put(question, &response);
+                }
+                response
+            }
+        };
+
+        match dns::rcode(&response) {
+            dns::RCODE_NO_ERROR => {}
+            dns::RCODE_NAME_ERROR => {
+                return Err(Error::new(
+                    ErrorKind::NotFound,
+                    format!("{} does not exist", name),
+                ))
+            }
+            rcode => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("resolve {} rcode {}", name, rcode),
+                ))
+            }
+        }
+        let ttl = dns::cache_ttl(&response).unwrap_or(0);
+        Ok((dns::addresses(&response)?, ttl))
+    }
+
+    /// Asks each upstream in turn until one answers, neither failing nor
+    /// refusing.
+    async fn exchange(&self, question: &dns::Question, query: &[u8]) -> io::Result<Vec<u8>> {
+        let mut last = Error::new(ErrorKind::NotFound, "no upstream");
+        for upstream in &self.upstreams {
+            let response =
+                match timeout(self.timeout, Self::exchange_udp(*upstream, question, query)).await {
+                    Err(_) => Err(Error::new(
+                        ErrorKind::TimedOut,
+                        format!("{} timed out", upstream),
+                    )),
+                    Ok(response) => response,
+                };
+            match response {
+                Err(err) => {
+                    debug!("resolve {} via {} {:?}", question.name, upstream, err);
+                    last = err;
+                }
+                Ok(response) => match dns::rcode(&response) {
+                    dns::RCODE_NO_ERROR | dns::RCODE_NAME_ERROR => return Ok(response),
+                    rcode => {
+                        debug!("resolve {} via {} rcode {}", question.name, upstream, rcode);
+                        last = Error::new(
+                            ErrorKind::InvalidData,
+                            format!("{} rcode {}", upstream, rcode),
+                        );
+                    }
+                },
+            }
+        }
+        Err(last)
+    }
+
+    /// Sends a query over UDP, retrying over TCP if the response is
+    /// truncated.
+    async fn exchange_udp(
+        upstream: SocketAddr,
+        question: &dns::Question,
+        query: &[u8],
+    ) -> io::Result<Vec<u8>> {
+        let bind = match upstream {
+            SocketAddr::V4(_) => "0.0.0.0:0",
+            SocketAddr::V6(_) => "[::]:0",
+        };
+        let mut socket = UdpSocket::bind(bind).await?;
+        socket.connect(upstream).await?;
+        socket.send(query).await?;
+        let mut buffer = vec![0_u8; 65536];
+        let response = loop {
+            let n = socket.recv(&mut buffer).await?;
+            // ignore stray datagrams
+            if Self::answers(&buffer[..n], question, query) {
+                break buffer[..n].to_vec();
+            }
+        };
+        // TC
+        if response[2] & 0x02 == 0 {
+            return Ok(response);
+        }
+
+        let mut s = TcpStream::connect(upstream).await?;
+        let mut message = (query.len() as u16).to_be_bytes().to_vec();
+        message.extend_from_slice(query);
+        s.write_all(&message).await?;
+        let mut len = [0_u8; 2];
+        s.read_exact(&mut len).await?;
+        let mut response = vec![0_u8; u16::from_be_bytes(len) as usize];
+        s.read_exact(&mut response).await?;
+        if !Self::answers(&response, question, query) {
+            return Err(Error::new(ErrorKind::InvalidData, "mismatched response"));
+        }
+        Ok(response)
+    }
+
+    fn answers(response: &[u8], question: &dns::Question, query: &[u8]) -> bool {
+        // QR
+        response.len() > 2
+            && response[2] & 0x80 != 0
+            && dns::id(response) == dns::id(query)
+            && match dns::Question::parse(response) {
+                Err(_) => false,
+                Ok((answered, _)) => answered == *question,
+            }
+    }
+}
+
+/// Name servers listed in `/etc/resolv.conf`.
+fn system_upstreams() -> Vec<SocketAddr> {
+    let text = match fs::read_to_string(RESOLV_CONF) {
+        Err(err) => {
+            warn!("read {} {:?}", RESOLV_CONF, err);
+            return vec![];
+        }
+        Ok(text) => text,
+    };
+    text.lines()
+        .filter_map(|line| {
+            let mut words = line.split_whitespace();
+            if words.next() != Some("nameserver") {
+                return None;
+            }
+            // drop any IPv6 zone
+            let ip = words.next()?.split('%').next()?;
+            Some(SocketAddr::new(ip.parse().ok()?, 53))
+        })
+        .collect()
+}
+
+/// Addresses by lower case name, in file order.
+fn parse_hosts(text: &str) -> HashMap<String, Vec<IpAddr>> {
+    let mut hosts: HashMap<String, Vec<IpAddr>> = HashMap::new();
+    for line in text.lines() {
+        let line = line.split('#').next().unwrap_or("");
+        let mut words = line.split_whitespace();
+        let ip: IpAddr = match words.next().and_then(|ip| ip.parse().ok()) {
+            None => continue,
+            Some(ip) => ip,
+        };
+        for name in words {
+            let addrs = hosts
+                .entry(name.trim_end_matches('.').to_ascii_lowercase())
+                .or_default();
+            if !addrs.contains(&ip) {
+                addrs.push(ip);
+            }
+        }
+    }
+    hosts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::time::Instant;
+    use tokio::net::TcpListener;
+    use tokio::spawn;
+    use tokio::time::delay_for;
+
+    const V4: &str = "192.0.2.1";
+    const V6: &str = "2001:db8::1";
+
+    /// What the stand-in name server does with a question.
+    enum Answer {
+        Addresses(u32),
+        NameError,
+        ServerFailure,
+        Silent,
+        // over UDP, the full answer coming over TCP only
+        Truncated,
+    }
+
+    /// Name server on 127.0.0.1, UDP and TCP on the same port, recording
+    /// the questions it gets along with whether they came over TCP.
+    struct NameServer {
+        addr: SocketAddr,
+        questions: Arc<Mutex<Vec<(dns::Question, bool)>>>,
+    }
+
+    impl NameServer {
+        async fn start(answer: fn(&dns::Question) -> Answer) -> NameServer {
+            let mut udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let addr = udp.local_addr().unwrap();
+            let mut tcp = TcpListener::bind(addr).await.unwrap();
+            let questions = Arc::new(Mutex::new(vec![]));
+
+            let udp_questions = questions.clone();
+            spawn(async move {
+                let mut buffer = vec![0_u8; 65536];
+                loop {
+                    let (n, peer) = udp.recv_from(&mut buffer).await.unwrap();
+                    let query = &buffer[..n];
+                    let (question, _) = dns::Question::parse(query).unwrap();
+                    udp_questions
+                        .lock()
+                        .unwrap()
+                        .push((question.clone(), false));
+                    let response = match answer(&question) {
+                        Answer::Silent => continue,
+                        Answer::Truncated => {
+                            let mut response = dns::error_response(query, dns::RCODE_NO_ERROR);
+                            response[2] |= 0x02;
+                            response
+                        }
+                        answer => respond(query, answer),
+                    };
+                    udp.send_to(&response, peer).await.unwrap();
+                }
+            });
+
+            let tcp_questions = questions.clone();
+            spawn(async move {
+                loop {
+                    let (mut s, _) = tcp.accept().await.unwrap();
+                    let mut len = [0_u8; 2];
+                    s.read_exact(&mut len).await.unwrap();
+                    let mut query = vec![0_u8; u16::from_be_bytes(len) as usize];
+                    s.read_exact(&mut query).await.unwrap();
+                    let (question, _) = dns::Question::parse(&query).unwrap();
+                    tcp_questions.lock().unwrap().push((question.clone(), true));
+                    let answer = match answer(&question) {
+                        Answer::Truncated => Answer::Addresses(60),
+                        answer => answer,
+                    };
+                    let response = respond(&query, answer);
+                    let mut message = (response.len() as u16).to_be_bytes().to_vec();
+                    message.extend_from_slice(&response);
+                    s.write_all(&message).await.unwrap();
+                }
+            });
+            NameServer { addr, questions }
+        }
+
+        fn questions(&self) -> Vec<(dns::Question, bool)> {
+            self.questions.lock().unwrap().clone()
+        }
+    }
+
+    fn respond(query: &[u8], answer: Answer) -> Vec<u8> {
+        match answer {
+            Answer::Addresses(ttl) => {
+                let addrs = [V4.parse().unwrap(), V6.parse().unwrap()];
+                dns::address_response(query, &addrs, ttl)
+            }
+            Answer::NameError => dns::error_response(query, dns::RCODE_NAME_ERROR),
+            _ => dns::error_response(query, dns::RCODE_SERVER_FAILURE),
+        }
+    }
+
+    fn resolver(upstreams: &[&NameServer], hosts_file: &str, prefer: Prefer) -> Resolver {
+        let upstreams = upstreams.iter().map(|server| server.addr).collect();
+        Resolver::new(upstreams, hosts_file, Duration::from_millis(200), prefer).unwrap()
+    }
+
+    fn ips(addrs: &[&str]) -> Vec<IpAddr> {
+        addrs.iter().map(|addr| addr.parse().unwrap()).collect()
+    }
+
+    #[tokio::test]
+    async fn ttl_expiry() {
+        let server = NameServer::start(|_| Answer::Addresses(1)).await;
+        let resolver = resolver(&[&server], "", Prefer::Ipv4);
+        let (addrs, ttl) = resolver.lookup("example.test").await.unwrap();
+        assert_eq!(addrs, ips(&[V4, V6]));
+        assert_eq!(ttl, 1);
+        resolver.lookup("Example.test.").await.unwrap();
+        assert_eq!(server.questions().len(), 2);
+        delay_for(Duration::from_millis(1100)).await;
+        resolver.lookup("example.test").await.unwrap();
+        assert_eq!(server.questions().len(), 4);
+    }
+
+    #[tokio::test]
+    async fn negative_caching() {
+        let server = NameServer::start(|_| Answer::NameError).await;
+        let resolver = resolver(&[&server], "", Prefer::Ipv4);
+        for _ in 0..2 {
+            let err = resolver.lookup("nx.test").await.unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::NotFound);
+        }
+        assert_eq!(server.questions().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn timeout_per_query() {
+        let silent = NameServer::start(|_| Answer::Silent).await;
+        let resolver = resolver(&[&silent], "", Prefer::Ipv4Only);
+        let start = Instant::now();
+        let err = resolver.lookup("slow.test").await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn fallback() {
+        let silent = NameServer::start(|_| Answer::Silent).await;
+        let failing = NameServer::start(|_| Answer::ServerFailure).await;
+        let server = NameServer::start(|_| Answer::Addresses(60)).await;
+        let resolver = resolver(&[&silent, &failing, &server], "", Prefer::Ipv4Only);
+        let (addrs, _) = resolver.lookup("example.test").await.unwrap();
+        assert_eq!(addrs, ips(&[V4]));
+        assert_eq!(silent.questions().len(), 1);
+        assert_eq!(failing.questions().len(), 1);
+        assert_eq!(server.questions().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn truncated_retried_over_tcp() {
+        let server = NameServer::start(|_| Answer::Truncated).await;
+        let resolver = resolver(&[&server], "", Prefer::Ipv4Only);
+        let (addrs, _) = resolver.lookup("big.test").await.unwrap();
+        assert_eq!(addrs, ips(&[V4]));
+        let tcp: Vec<bool> = server.questions().iter().map(|(_, tcp)| *tcp).collect();
+        assert_eq!(tcp, vec![false, true]);
+    }
+
+    #[tokio::test]
+    async fn hosts_first() {
+        let server = NameServer::start(|_| Answer::Addresses(60)).await;
+        let hosts_file = std::env::temp_dir().join(format!("hosts-{}", std::process::id()));
+        fs::write(
+            &hosts_file,
+            "# comment\n198.51.100.7 Local.Test alias # inline\n",
+        )
+        .unwrap();
+        let resolver = resolver(&[&server], hosts_file.to_str().unwrap(), Prefer::Ipv4);
+        fs::remove_file(&hosts_file).unwrap();
+        assert_eq!(
+            resolver.lookup("local.test").await.unwrap(),
+            (ips(&["198.51.100.7"]), HOSTS_TTL)
+        );
+        assert_eq!(
+            resolver.lookup("ALIAS.").await.unwrap().0,
+            ips(&["198.51.100.7"])
+        );
+        assert!(server.questions().is_empty());
+        resolver.lookup("other.test").await.unwrap();
+        assert_eq!(server.questions().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn prefer() {
+        let server = NameServer::start(|_| Answer::Addresses(60)).await;
+        let cases = [
+            (
+                Prefer::Ipv4,
+                vec![V4, V6],
+                vec![dns::TYPE_A, dns::TYPE_AAAA],
+            ),
+            (
+                Prefer::Ipv6,
+                vec![V6, V4],
+                vec![dns::TYPE_A, dns::TYPE_AAAA],
+            ),
+            (Prefer::Ipv4Only, vec![V4], vec![dns::TYPE_A]),
+            (Prefer::Ipv6Only, vec![V6], vec![dns::TYPE_AAAA]),
+        ];
+        for (prefer, expected, qtypes) in cases.iter() {
+            server.questions.lock().unwrap().clear();
+            let resolver = resolver(&[&server], "", *prefer);
+            let (addrs, _) = resolver.lookup("example.test").await.unwrap();
+            assert_eq!(addrs, ips(expected), "{:?}", prefer);
+            let mut asked: Vec<u16> = server.questions().iter().map(|(q, _)| q.qtype).collect();
+            asked.sort_unstable();
+            assert_eq!(&asked, qtypes, "{:?}", prefer);
+        }
+        let resolver = resolver(&[&server], "", Prefer::Ipv4);
+        assert_eq!(
+            resolver.lookup("[2001:db8::2]").await.unwrap().0,
+            ips(&["2001:db8::2"])
+        );
+    }
+}
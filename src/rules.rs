@@ -0,0 +1,325 @@
+use crate::acl::{parse_ports, Cidr};
+use crate::address::Address;
+use crate::resolver::{Prefer, Resolver, HOSTS_FILE};
+use regex::Regex;
+use std::error::Error;
+use std::fs;
+use std::net::IpAddr;
+use std::time::Duration;
+use tokio::time::timeout;
+
+// How long an `ip-cidr` rule marked `resolve` waits for the addresses of a
+// domain target.
+const RESOLVE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Where a connection goes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Action {
+    Direct,
+    Proxy,
+    Reject,
+}
+
+impl Action {
+    pub fn parse(s: &str) -> Option<Action> {
+        match s {
+            "direct" => Some(Action::Direct),
+            "proxy" => Some(Action::Proxy),
+            "reject" => Some(Action::Reject),
+            _ => None,
+        }
+    }
+}
+
+enum Matcher {
+    Domain(String),
+    // the domain itself and its subdomains
+    DomainSuffix(String),
+    Keyword(String),
+    Regex(Regex),
+    // with whether domain targets are resolved to be matched
+    Cidr(Cidr, bool),
+    Port(u16, u16),
+    // protocol of the listener the client came in on
+    Inbound(String),
+}
+
+pub struct Rule {
+    matcher: Matcher,
+    action: Action,
+}
+
+impl Rule {
+    /// Parses `kind:value=action`, the kinds being `domain`, `domain-suffix`,
+    /// `domain-keyword`, `domain-regex`, `ip-cidr`, `port` and `inbound`.
+    /// Without `=action`, the rule takes `action`. `ip-cidr:cidr,resolve`
+    /// also matches domain targets, looking them up locally.
+    pub fn parse(rule: &str, action: Option<Action>) -> Option<Rule> {
+        let i = rule.find(':')?;
+        let (kind, value) = (&rule[..i], &rule[i + 1..]);
+        let split = value
+            .rfind('=')
+            .map(|j| (&value[..j], Action::parse(&value[j + 1..])));
+        let (value, action) = match split {
+            Some((value, Some(action))) => (value, action),
+            _ => (value, action?),
+        };
+        if value.is_empty() {
+            return None;
+        }
+        let domain = || value.trim_end_matches('.').to_ascii_lowercase();
+        let matcher = match kind {
+            "domain" => Matcher::Domain(domain()),
+            "domain-suffix" => Matcher::DomainSuffix(domain().trim_start_matches('.').to_string()),
+            "domain-keyword" => Matcher::Keyword(value.to_ascii_lowercase()),
+            "domain-regex" => Matcher::Regex(Regex::new(value).ok()?),
+            "ip-cidr" => match value.strip_suffix(",resolve") {
+                None => Matcher::Cidr(Cidr::parse(value)?, false),
+                Some(cidr) => Matcher::Cidr(Cidr::parse(cidr)?, true),
+            },
+            "port" => {
+                let (low, high) = parse_ports(value)?;
+                Matcher::Port(low, high)
+            }
+            "inbound" => Matcher::Inbound(value.to_string()),
+            _ => return None,
+        };
+        Some(Rule { matcher, action })
+    }
+
+    /// Reads rules from `file`, one per line, skipping blank lines and `#`
+    /// comments. Lines without `=action` take `action`.
+    pub fn load(file: &str, action: Option<Action>) -> Result<Vec<Rule>, Box<dyn Error>> {
+        let text = fs::read_to_string(file).map_err(|err| format!("{} {}", file, err))?;
+        let mut rules = vec![];
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match Rule::parse(line, action) {
+                None => return Err(format!("{}:{} rule error {:?}", file, n + 1, line).into()),
+                Some(rule) => rules.push(rule),
+            }
+        }
+        Ok(rules)
+    }
+}
+
+/// Rules deciding, per TCP connection, whether it goes directly, through
+/// the remote or nowhere. The first matching rule wins. UDP and DNS always
+/// go through the remote.
+pub struct Rules {
+    rules: Vec<Rule>,
+    default: Action,
+    // only when some rule resolves domain targets
+    resolver: Option<Resolver>,
+}
+
+impl Rules {
+    /// Fails when a rule resolves domain targets and there is no name
+    /// server to ask.
+    pub fn new(rules: Vec<Rule>, default: Action) -> Result<Rules, Box<dyn Error>> {
+        let resolves = rules
+            .iter()
+            .any(|rule| matches!(rule.matcher, Matcher::Cidr(_, true)));
+        let resolver = if resolves {
+            let resolver = Resolver::new(vec![], HOSTS_FILE, RESOLVE_TIMEOUT, Prefer::Ipv4)
+                .map_err(|err| format!("rules resolver {}", err))?;
+            Some(resolver)
+        } else {
+            None
+        };
+        Ok(Rules {
+            rules,
+            default,
+            resolver,
+        })
+    }
+
+    /// The action for a connection to `target` from a client of the
+    /// `inbound` listener. `ip` is the address a transparent client
+    /// connected to when `target` is the name it sniffed. Other domain
+    /// targets only reach `ip-cidr` rules marked `resolve`, being looked up
+    /// then, once.
+    pub async fn action(&self, target: &Address, ip: Option<IpAddr>, inbound: &str) -> Action {
+        let host = match target {
+            Address::Domain(host, _) => Some(host.trim_end_matches('.').to_ascii_lowercase()),
+            Address::Ip(_) => None,
+        };
+        let known = match (target, ip) {
+            (Address::Ip(addr), _) => vec![addr.ip()],
+            (Address::Domain(..), ip) => ip.into_iter().collect(),
+        };
+        let mut resolved = None;
+        for rule in &self.rules {
+            let matched = match (&rule.matcher, &host) {
+                (Matcher::Domain(domain), Some(host)) => host == domain,
+                (Matcher::DomainSuffix(suffix), Some(host)) => {
+                    host == suffix
+                        || (host.ends_with(suffix.as_str())
+                            && host[..host.len() - suffix.len()].ends_with('.'))
+                }
+                (Matcher::Keyword(keyword), Some(host)) => host.contains(keyword.as_str()),
+                (Matcher::Regex(regex), Some(host)) => regex.is_match(host),
+                (Matcher::Cidr(cidr, _), _) if known.iter().any(|ip| cidr.contains(ip)) => true,
+                (Matcher::Cidr(cidr, true), Some(host)) if known.is_empty() => {
+                    if resolved.is_none() {
+                        resolved = Some(self.resolve(host).await);
+                    }
+                    resolved.iter().flatten().any(|ip| cidr.contains(ip))
+                }
+                (Matcher::Port(low, high), _) => *low <= target.port() && target.port() <= *high,
+                (Matcher::Inbound(name), _) => name == inbound,
+                _ => false,
+            };
+            if matched {
+                return rule.action;
+            }
+        }
+        self.default
+    }
+
+    async fn resolve(&self, host: &str) -> Vec<IpAddr> {
+        let resolver = match &self.resolver {
+            None => return vec![],
+            Some(resolver) => resolver,
+        };
+        match timeout(RESOLVE_TIMEOUT, resolver.lookup(host)).await {
+            Err(_) => {
+                debug!("rules resolve {} timed out", host);
+                vec![]
+            }
+            Ok(Err(err)) => {
+                debug!("rules resolve {} {:?}", host, err);
+                vec![]
+            }
+            Ok(Ok((addrs, _))) => addrs,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(rules: &[&str]) -> Rules {
+        let rules = rules
+            .iter()
+            .map(|rule| Rule::parse(rule, Some(Action::Direct)).unwrap())
+            .collect();
+        Rules::new(rules, Action::Proxy).unwrap()
+    }
+
+    fn domain(host: &str, port: u16) -> Address {
+        Address::Domain(host.to_string(), port)
+    }
+
+    fn ip(addr: &str) -> Address {
+        Address::Ip(addr.parse().unwrap())
+    }
+
+    #[test]
+    fn parse() {
+        let rule = Rule::parse("domain:Example.COM.=reject", None).unwrap();
+        assert!(matches!(&rule.matcher, Matcher::Domain(domain) if domain == "example.com"));
+        assert_eq!(rule.action, Action::Reject);
+        let rule = Rule::parse("domain-suffix:.example.com", Some(Action::Direct)).unwrap();
+        assert!(matches!(&rule.matcher, Matcher::DomainSuffix(suffix) if suffix == "example.com"));
+        assert_eq!(rule.action, Action::Direct);
+        // an `=` not followed by an action is part of the value
+        let rule = Rule::parse("domain-regex:^a=b$", Some(Action::Proxy)).unwrap();
+        assert!(matches!(&rule.matcher, Matcher::Regex(regex) if regex.is_match("a=b")));
+        let rule = Rule::parse("ip-cidr:10.0.0.0/8,resolve=direct", None).unwrap();
+        assert!(matches!(rule.matcher, Matcher::Cidr(_, true)));
+        let rule = Rule::parse("ip-cidr:10.0.0.0/8=direct", None).unwrap();
+        assert!(matches!(rule.matcher, Matcher::Cidr(_, false)));
+        let rule = Rule::parse("port:8000-8080=proxy", None).unwrap();
+        assert!(matches!(rule.matcher, Matcher::Port(8000, 8080)));
+
+        for rule in &[
+            "domain:example.com",
+            "domain:=direct",
+            "bogus:x=direct",
+            "domain-regex:(=direct",
+            "ip-cidr:10.0.0.0/33=direct",
+            "ip-cidr:10.0.0.0/8,no-resolve=direct",
+            "port:9-1=direct",
+            "no-colon=direct",
+        ] {
+            assert!(Rule::parse(rule, None).is_none(), "{}", rule);
+        }
+    }
+
+    #[tokio::test]
+    async fn domain_suffix() {
+        let rules = rules(&["domain-suffix:example.org"]);
+        for host in &["example.org", "www.example.org", "a.b.Example.org."] {
+            assert_eq!(
+                rules.action(&domain(host, 80), None, "socks").await,
+                Action::Direct
+            );
+        }
+        for host in &["badexample.org", "example.org.evil", "org"] {
+            assert_eq!(
+                rules.action(&domain(host, 80), None, "socks").await,
+                Action::Proxy
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn first_match() {
+        let rules = rules(&[
+            "domain-keyword:ads=reject",
+            "port:22",
+            "inbound:tunnel=reject",
+            "domain:ads.example.com",
+        ]);
+        let cases = [
+            (domain("ads.example.com", 80), "socks", Action::Reject),
+            (ip("192.0.2.1:22"), "tunnel", Action::Direct),
+            (ip("192.0.2.1:80"), "tunnel", Action::Reject),
+            (ip("192.0.2.1:80"), "http", Action::Proxy),
+        ];
+        for (target, inbound, action) in cases.iter() {
+            assert_eq!(
+                rules.action(target, None, inbound).await,
+                *action,
+                "{}",
+                target
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn cidr_known_addresses_only() {
+        let rules = rules(&["ip-cidr:192.0.2.0/24"]);
+        assert_eq!(
+            rules.action(&ip("192.0.2.7:443"), None, "redir").await,
+            Action::Direct
+        );
+        assert_eq!(
+            rules
+                .action(&ip("[::ffff:192.0.2.7]:443"), None, "redir")
+                .await,
+            Action::Direct
+        );
+        assert_eq!(
+            rules.action(&ip("198.51.100.1:443"), None, "redir").await,
+            Action::Proxy
+        );
+        // a sniffed name is matched by the address the client connected to
+        let target = domain("example.com", 443);
+        let sniffed = Some("192.0.2.7".parse().unwrap());
+        assert_eq!(
+            rules.action(&target, sniffed, "redir").await,
+            Action::Direct
+        );
+        // other names are left unresolved
+        assert_eq!(
+            rules.action(&domain("localhost", 443), None, "socks").await,
+            Action::Proxy
+        );
+    }
+}
@@ -0,0 +1,206 @@
+use crate::address::Address;
+use crate::http;
+
+const TLS_HANDSHAKE: u8 = 0x16;
+const TLS_CLIENT_HELLO: u8 = 0x01;
+const TLS_EXT_SERVER_NAME: u16 = 0x0000;
+const TLS_NAME_TYPE_HOST: u8 = 0x00;
+
+// largest TLS record
+const MAX_RECORD_LEN: usize = 16 * 1024;
+
+/// Outcome of looking for a host name in the first bytes of a connection.
+pub enum Sniff {
+    Found(String),
+    NotFound,
+    NeedMore,
+}
+
+/// Looks for the host name in a TLS ClientHello (SNI) or in the `Host`
+/// header of an HTTP request.
+pub fn host(buf: &[u8]) -> Sniff {
+    match buf.first() {
+        None => Sniff::NeedMore,
+        Some(&TLS_HANDSHAKE) => tls_server_name(buf),
+        Some(b'A'..=b'Z') => http_host(buf),
+        Some(_) => Sniff::NotFound,
+    }
+}
+
+fn http_host(buf: &[u8]) -> Sniff {
+    let len = match http::head_len(buf) {
+        Err(_) => return Sniff::NotFound,
+        Ok(None) => return Sniff::NeedMore,
+        Ok(Some(len)) => len,
+    };
+    let host = http::parse_request(&buf[..len])
+        .ok()
+        .and_then(|head| http::parse_authority(head.header("Host")?, 80));
+    match host {
+        Some(Address::Domain(host, _)) => Sniff::Found(host),
+        _ => Sniff::NotFound,
+    }
+}
+
+/// Reads big endian length prefixed fields.
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.buf.len() < n {
+            return None;
+        }
+        let (head, rest) = self.buf.split_at(n);
+        self.buf = rest;
+        Some(head)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn vec8(&mut self) -> Option<&'a [u8]> {
+        let n = self.u8()? as usize;
+        self.take(n)
+    }
+
+    fn vec16(&mut self) -> Option<&'a [u8]> {
+        let n = self.u16()? as usize;
+        self.take(n)
+    }
+}
+
+fn tls_server_name(buf: &[u8]) -> Sniff {
+    // record header: type, version, length
+    if buf.len() < 5 {
+        return Sniff::NeedMore;
+    }
+    let record_len = u16::from_be_bytes([buf[3], buf[4]]) as usize;
+    if record_len > MAX_RECORD_LEN {
+        return Sniff::NotFound;
+    }
+    if buf.len() < 5 + record_len {
+        return Sniff::NeedMore;
+    }
+    match client_hello_server_name(&buf[5..5 + record_len]) {
+        None => Sniff::NotFound,
+        Some(name) => Sniff::Found(name),
+    }
+}
+
+fn client_hello_server_name(record: &[u8]) -> Option<String> {
+    let mut r = Reader { buf: record };
+    if r.u8()? != TLS_CLIENT_HELLO {
+        return None;
+    }
+    // length, version, random, session id, cipher suites, compression
+    r.take(3 + 2 + 32)?;
+    r.vec8()?;
+    r.vec16()?;
+    r.vec8()?;
+    let mut extensions = Reader { buf: r.vec16()? };
+    while let Some(ext_type) = extensions.u16() {
+        let data = extensions.vec16()?;
+        if ext_type != TLS_EXT_SERVER_NAME {
+            continue;
+        }
+        let mut names = Reader {
+            buf: Reader { buf: data }.vec16()?,
+        };
+        while let Some(name_type) = names.u8() {
+            let name = names.vec16()?;
+            if name_type != TLS_NAME_TYPE_HOST {
+                continue;
+            }
+            let name = std::str::from_utf8(name).ok()?;
+            return match http::parse_authority(name, 0)? {
+                Address::Domain(name, _) => Some(name),
+                Address::Ip(_) => None,
+            };
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vec16(data: &[u8]) -> Vec<u8> {
+        let mut v = (data.len() as u16).to_be_bytes().to_vec();
+        v.extend_from_slice(data);
+        v
+    }
+
+    fn client_hello(name: &str) -> Vec<u8> {
+        let mut names = vec![TLS_NAME_TYPE_HOST];
+        names.extend(vec16(name.as_bytes()));
+        let mut ext = TLS_EXT_SERVER_NAME.to_be_bytes().to_vec();
+        ext.extend(vec16(&vec16(&names)));
+        // an extension before the server name one, to be skipped
+        let mut extensions = vec![0x00, 0x0b];
+        extensions.extend(vec16(&[0x01, 0x00]));
+        extensions.extend(ext);
+        let mut body = vec![0x03, 0x03];
+        body.extend_from_slice(&[0; 32]);
+        body.push(0);
+        body.extend(vec16(&[0x13, 0x01]));
+        body.extend_from_slice(&[1, 0]);
+        body.extend(vec16(&extensions));
+        let mut handshake = vec![TLS_CLIENT_HELLO, 0, 0, body.len() as u8];
+        handshake.extend(body);
+        let mut record = vec![TLS_HANDSHAKE, 0x03, 0x01];
+        record.extend(vec16(&handshake));
+        record
+    }
+
+    fn found(buf: &[u8]) -> Option<String> {
+        match host(buf) {
+            Sniff::Found(host) => Some(host),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn tls_server_name() {
+        let hello = client_hello("example.com");
+        assert_eq!(found(&hello).as_deref(), Some("example.com"));
+        for len in 0..hello.len() {
+            assert!(matches!(host(&hello[..len]), Sniff::NeedMore), "{}", len);
+        }
+        assert!(matches!(host(&client_hello("192.0.2.1")), Sniff::NotFound));
+    }
+
+    #[test]
+    fn tls_not_client_hello() {
+        let mut hello = client_hello("example.com");
+        hello[5] = 0x02;
+        assert!(matches!(host(&hello), Sniff::NotFound));
+        let mut hello = client_hello("example.com");
+        hello[3] = 0xff;
+        assert!(matches!(host(&hello), Sniff::NotFound));
+    }
+
+    #[test]
+    fn http_host() {
+        let request = b"GET / HTTP/1.1\r\nHost: Example.com:8080\r\n\r\n";
+        assert_eq!(found(request).as_deref(), Some("Example.com"));
+        assert!(matches!(host(&request[..20]), Sniff::NeedMore));
+        assert!(matches!(
+            host(b"GET / HTTP/1.1\r\nHost: 192.0.2.1\r\n\r\n"),
+            Sniff::NotFound
+        ));
+        assert!(matches!(
+            host(b"GET / HTTP/1.1\r\nAccept: */*\r\n\r\n"),
+            Sniff::NotFound
+        ));
+        assert!(matches!(host(b"\x00\x01binary"), Sniff::NotFound));
+        assert!(matches!(host(b""), Sniff::NeedMore));
+    }
+}
@@ -0,0 +1,108 @@
+use crate::address::Address;
+use crate::request::Request;
+use crate::socks5;
+use std::collections::HashMap;
+use std::io;
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use tokio::net::TcpStream;
+use tokio::prelude::*;
+
+pub const VERSION: u8 = 0x04;
+
+const CMD_CONNECT: u8 = 0x01;
+
+const REPLY_VERSION: u8 = 0x00;
+const REP_GRANTED: u8 = 90;
+const REP_REJECTED: u8 = 91;
+
+// longest USERID or SOCKS4a hostname accepted
+const MAX_FIELD_LEN: usize = 255;
+
+/// Reads a SOCKS4 or SOCKS4a CONNECT request and returns it as the
+/// equivalent SOCKS5 request. The reply is left to the caller.
+///
+/// SOCKS4 has no passwords, so it is refused when `users` is not empty.
+pub async fn handshake(s: &mut TcpStream, users: &HashMap<String, String>) -> io::Result<Request> {
+    let mut data = [0_u8; 8];
+    s.read_exact(&mut data).await?;
+    if data[0] != VERSION {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("unsupported version {:#04x}", data[0]),
+        ));
+    }
+    let port = u16::from_be_bytes([data[2], data[3]]);
+    let ip = Ipv4Addr::new(data[4], data[5], data[6], data[7]);
+
+    // USERID, not verified
+    read_field(s).await?;
+
+    // SOCKS4a: 0.0.0.x with x != 0 means a hostname follows
+    let octets = ip.octets();
+    let target = if octets[..3] == [0, 0, 0] && octets[3] != 0 {
+        let host = read_field(s).await?;
+        match String::from_utf8(host) {
+            Err(err) => return Err(Error::new(ErrorKind::InvalidData, err)),
+            Ok(host) => Address::Domain(host, port),
+        }
+    } else {
+        Address::Ip(SocketAddr::new(IpAddr::V4(ip), port))
+    };
+
+    if data[1] != CMD_CONNECT {
+        reply(
+            s,
+            socks5::REP_COMMAND_NOT_SUPPORTED,
+            &Address::unspecified(),
+        )
+        .await?;
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("unsupported command {:#04x}", data[1]),
+        ));
+    }
+    if !users.is_empty() {
+        reply(s, socks5::REP_GENERAL_FAILURE, &Address::unspecified()).await?;
+        return Err(Error::new(
+            ErrorKind::PermissionDenied,
+            "authentication required",
+        ));
+    }
+    Ok(Request::new(socks5::CMD_CONNECT, None, target))
+}
+
+/// Sends the reply to a SOCKS4 request, mapping the SOCKS5 reply code `rep`
+/// onto granted or rejected.
+pub async fn reply<W: AsyncWrite + Unpin>(s: &mut W, rep: u8, bind: &Address) -> io::Result<()> {
+    let cd = if rep == socks5::REP_SUCCEEDED {
+        REP_GRANTED
+    } else {
+        REP_REJECTED
+    };
+    let mut resp = vec![REPLY_VERSION, cd];
+    match bind {
+        Address::Ip(SocketAddr::V4(addr)) => {
+            resp.extend_from_slice(&addr.port().to_be_bytes());
+            resp.extend_from_slice(&addr.ip().octets());
+        }
+        _ => resp.extend_from_slice(&[0_u8; 6]),
+    }
+    s.write_all(&resp).await
+}
+
+/// Reads a NUL terminated field.
+async fn read_field(s: &mut TcpStream) -> io::Result<Vec<u8>> {
+    let mut field = vec![];
+    let mut byte = [0_u8; 1];
+    loop {
+        s.read_exact(&mut byte).await?;
+        if byte[0] == 0x00 {
+            return Ok(field);
+        }
+        if field.len() >= MAX_FIELD_LEN {
+            return Err(Error::new(ErrorKind::InvalidData, "field too long"));
+        }
+        field.push(byte[0]);
+    }
+}
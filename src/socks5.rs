@@ -0,0 +1,175 @@
+use crate::address::Address;
+use crate::request::Request;
+use openssl::memcmp;
+use std::collections::HashMap;
+use std::io;
+use std::io::{Error, ErrorKind};
+use tokio::net::TcpStream;
+use tokio::prelude::*;
+
+pub const VERSION: u8 = 0x05;
+
+pub const METHOD_NO_AUTH: u8 = 0x00;
+pub const METHOD_USER_PASS: u8 = 0x02;
+pub const METHOD_NO_ACCEPTABLE: u8 = 0xFF;
+
+pub const CMD_CONNECT: u8 = 0x01;
+pub const CMD_BIND: u8 = 0x02;
+pub const CMD_UDP_ASSOCIATE: u8 = 0x03;
+
+pub const REP_SUCCEEDED: u8 = 0x00;
+pub const REP_GENERAL_FAILURE: u8 = 0x01;
+pub const REP_CONNECTION_NOT_ALLOWED: u8 = 0x02;
+pub const REP_NETWORK_UNREACHABLE: u8 = 0x03;
+pub const REP_HOST_UNREACHABLE: u8 = 0x04;
+pub const REP_CONNECTION_REFUSED: u8 = 0x05;
+pub const REP_TTL_EXPIRED: u8 = 0x06;
+pub const REP_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+pub const REP_ADDRESS_TYPE_NOT_SUPPORTED: u8 = 0x08;
+
+// RFC 1929 username/password sub-negotiation
+const USER_PASS_VERSION: u8 = 0x01;
+const USER_PASS_SUCCEEDED: u8 = 0x00;
+const USER_PASS_FAILED: u8 = 0x01;
+
+/// Runs the SOCKS5 negotiation with a local client and returns the
+/// requested target. The CONNECT reply is left to the caller.
+///
+/// When `users` is not empty, clients must authenticate with one of its
+/// name/password pairs.
+pub async fn handshake(s: &mut TcpStream, users: &HashMap<String, String>) -> io::Result<Request> {
+    // method selection
+    let mut data = [0_u8; 2];
+    s.read_exact(&mut data).await?;
+    if data[0] != VERSION {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("unsupported version {:#04x}", data[0]),
+        ));
+    }
+    let mut methods = vec![0_u8; data[1] as usize];
+    s.read_exact(&mut methods).await?;
+    let method = if users.is_empty() {
+        METHOD_NO_AUTH
+    } else {
+        METHOD_USER_PASS
+    };
+    if !methods.contains(&method) {
+        s.write_all(&[VERSION, METHOD_NO_ACCEPTABLE]).await?;
+        return Err(Error::new(
+            ErrorKind::PermissionDenied,
+            format!("no acceptable method in {:?}", methods),
+        ));
+    }
+    s.write_all(&[VERSION, method]).await?;
+
+    let user = if method == METHOD_USER_PASS {
+        Some(authenticate(s, users).await?)
+    } else {
+        None
+    };
+
+    // request
+    let mut data = [0_u8; 3];
+    s.read_exact(&mut data).await?;
+    if data[0] != VERSION || data[2] != 0x00 {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("unsupported request {:?}", data),
+        ));
+    }
+    if data[1] != CMD_CONNECT && data[1] != CMD_BIND && data[1] != CMD_UDP_ASSOCIATE {
+        reply(s, REP_COMMAND_NOT_SUPPORTED, &Address::unspecified()).await?;
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("unsupported command {:#04x}", data[1]),
+        ));
+    }
+    match Address::read_from(s).await {
+        Err(err) if err.kind() == ErrorKind::InvalidData => {
+            reply(s, REP_ADDRESS_TYPE_NOT_SUPPORTED, &Address::unspecified()).await?;
+            Err(err)
+        }
+        Err(err) => Err(err),
+        Ok(target) => Ok(Request::new(data[1], user, target)),
+    }
+}
+
+/// Checks a SOCKS5 UDP request header, `RSV FRAG ATYP DST.ADDR DST.PORT`,
+/// and returns the destination together with the header length.
+/// Fragmented datagrams are not supported.
+pub fn parse_udp_header(buf: &[u8]) -> io::Result<(Address, usize)> {
+    if buf.len() < 3 {
+        return Err(Error::new(ErrorKind::UnexpectedEof, "udp header truncated"));
+    }
+    if buf[2] != 0x00 {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("udp fragment {:#04x}", buf[2]),
+        ));
+    }
+    let (target, n) = Address::from_bytes(&buf[3..])?;
+    Ok((target, 3 + n))
+}
+
+/// Maps a failed outbound connect onto a SOCKS5 reply code.
+pub fn reply_code(err: &io::Error) -> u8 {
+    match (err.raw_os_error(), err.kind()) {
+        (Some(libc::ENETUNREACH), _) | (Some(libc::ENETDOWN), _) => REP_NETWORK_UNREACHABLE,
+        (Some(libc::EHOSTUNREACH), _) | (Some(libc::EHOSTDOWN), _) => REP_HOST_UNREACHABLE,
+        (_, ErrorKind::ConnectionRefused) => REP_CONNECTION_REFUSED,
+        (_, ErrorKind::TimedOut) => REP_TTL_EXPIRED,
+        // denied by our own policy rather than the system
+        (None, ErrorKind::PermissionDenied) => REP_CONNECTION_NOT_ALLOWED,
+        // names resolving to no address carry no errno
+        (None, ErrorKind::NotFound) => REP_HOST_UNREACHABLE,
+        _ => REP_GENERAL_FAILURE,
+    }
+}
+
+async fn authenticate(s: &mut TcpStream, users: &HashMap<String, String>) -> io::Result<String> {
+    let mut data = [0_u8; 2];
+    s.read_exact(&mut data).await?;
+    if data[0] != USER_PASS_VERSION {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("unsupported auth version {:#04x}", data[0]),
+        ));
+    }
+    let mut name = vec![0_u8; data[1] as usize];
+    s.read_exact(&mut name).await?;
+
+    let mut password_len = [0_u8; 1];
+    s.read_exact(&mut password_len).await?;
+    let mut password = vec![0_u8; password_len[0] as usize];
+    s.read_exact(&mut password).await?;
+
+    let name = String::from_utf8_lossy(&name).into_owned();
+    match users.get(&name) {
+        Some(expected) if password_eq(expected.as_bytes(), &password) => {
+            s.write_all(&[USER_PASS_VERSION, USER_PASS_SUCCEEDED])
+                .await?;
+            Ok(name)
+        }
+        _ => {
+            s.write_all(&[USER_PASS_VERSION, USER_PASS_FAILED]).await?;
+            Err(Error::new(
+                ErrorKind::PermissionDenied,
+                format!("authentication failed for {:?}", name),
+            ))
+        }
+    }
+}
+
+/// Compares passwords in constant time, so that timing tells nothing about
+/// how much of a guess was right.
+pub fn password_eq(expected: &[u8], password: &[u8]) -> bool {
+    expected.len() == password.len() && memcmp::eq(expected, password)
+}
+
+/// Sends the reply to a SOCKS5 request, `bind` being BND.ADDR/BND.PORT.
+pub async fn reply<W: AsyncWrite + Unpin>(s: &mut W, rep: u8, bind: &Address) -> io::Result<()> {
+    let mut resp = vec![VERSION, rep, 0x00];
+    resp.extend_from_slice(&bind.to_bytes());
+    s.write_all(&resp).await
+}
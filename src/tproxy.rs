@@ -0,0 +1,260 @@
+use crate::acl;
+use crate::redir;
+use socket2::{Domain, SockAddr, Socket, Type};
+use std::io;
+use std::io::{Error, ErrorKind};
+use std::mem;
+use std::net::{IpAddr, SocketAddr};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::ptr;
+use std::task::{Context, Poll};
+use tokio::future::poll_fn;
+use tokio::io::PollEvented;
+use tokio::net::{TcpListener, UdpSocket};
+
+// missing from libc
+#[cfg(target_os = "linux")]
+const IPV6_TRANSPARENT: libc::c_int = 75;
+
+fn setsockopt(fd: RawFd, level: libc::c_int, name: libc::c_int) -> io::Result<()> {
+    let on: libc::c_int = 1;
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            &on as *const _ as *const libc::c_void,
+            mem::size_of_val(&on) as libc::socklen_t,
+        )
+    };
+    if ret != 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Lets the socket accept traffic for, and send from, addresses that are not
+/// local. Needs `CAP_NET_ADMIN`.
+#[cfg(target_os = "linux")]
+fn set_transparent(socket: &Socket, ipv6: bool) -> io::Result<()> {
+    if ipv6 {
+        setsockopt(socket.as_raw_fd(), libc::SOL_IPV6, IPV6_TRANSPARENT)
+    } else {
+        setsockopt(socket.as_raw_fd(), libc::SOL_IP, libc::IP_TRANSPARENT)
+    }
+}
+
+/// Asks for the original destination of each datagram.
+#[cfg(target_os = "linux")]
+fn set_recv_orig_dst(socket: &Socket, ipv6: bool) -> io::Result<()> {
+    if ipv6 {
+        setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_IPV6,
+            libc::IPV6_RECVORIGDSTADDR,
+        )
+    } else {
+        setsockopt(socket.as_raw_fd(), libc::SOL_IP, libc::IP_RECVORIGDSTADDR)
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn is_orig_dst(cmsg: &libc::cmsghdr) -> bool {
+    (cmsg.cmsg_level == libc::SOL_IP && cmsg.cmsg_type == libc::IP_ORIGDSTADDR)
+        || (cmsg.cmsg_level == libc::SOL_IPV6 && cmsg.cmsg_type == libc::IPV6_ORIGDSTADDR)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_transparent(_socket: &Socket, _ipv6: bool) -> io::Result<()> {
+    Err(Error::new(
+        ErrorKind::Other,
+        "TPROXY is only available on Linux",
+    ))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_recv_orig_dst(_socket: &Socket, _ipv6: bool) -> io::Result<()> {
+    Err(Error::new(
+        ErrorKind::Other,
+        "TPROXY is only available on Linux",
+    ))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn is_orig_dst(_cmsg: &libc::cmsghdr) -> bool {
+    false
+}
+
+fn transparent_socket(addr: &SocketAddr, ty: Type) -> io::Result<Socket> {
+    let domain = match addr {
+        SocketAddr::V4(_) => Domain::ipv4(),
+        SocketAddr::V6(_) => Domain::ipv6(),
+    };
+    let socket = Socket::new(domain, ty, None)?;
+    socket.set_reuse_address(true)?;
+    set_transparent(&socket, addr.is_ipv6())?;
+    Ok(socket)
+}
+
+/// Binds a TCP listener for connections diverted by an iptables `TPROXY`
+/// rule. The local address of an accepted connection is the one the client
+/// asked for.
+pub fn tcp_listener(addr: &SocketAddr) -> io::Result<TcpListener> {
+    let socket = transparent_socket(addr, Type::stream())?;
+    socket.bind(&SockAddr::from(*addr))?;
+    socket.listen(1024)?;
+    socket.set_nonblocking(true)?;
+    TcpListener::from_std(socket.into_tcp_listener())
+}
+
+/// Binds a UDP socket to a foreign address, to answer a client as the host
+/// it originally sent to.
+pub fn udp_sender(addr: &SocketAddr) -> io::Result<UdpSocket> {
+    let socket = transparent_socket(addr, Type::dgram())?;
+    socket.bind(&SockAddr::from(*addr))?;
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket.into_udp_socket())
+}
+
+/// Whether a datagram was sent to the listener on `listen` itself rather
+/// than diverted to it, the listener possibly bound to every address.
+pub fn is_listener(dst: &SocketAddr, listen: &SocketAddr) -> bool {
+    if dst.port() != listen.port() {
+        return false;
+    }
+    let ip = acl::unmap(&dst.ip());
+    if !listen.ip().is_unspecified() {
+        return ip == acl::unmap(&listen.ip());
+    }
+    if ip.is_loopback() {
+        return true;
+    }
+    match local_ips() {
+        Err(err) => {
+            debug!("local_ips {:?}", err);
+            false
+        }
+        Ok(ips) => ips.contains(&ip),
+    }
+}
+
+/// Addresses of the host's interfaces.
+fn local_ips() -> io::Result<Vec<IpAddr>> {
+    let mut ifaddrs: *mut libc::ifaddrs = ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut ifaddrs) } != 0 {
+        return Err(Error::last_os_error());
+    }
+    let mut ips = vec![];
+    let mut ifa = ifaddrs;
+    while !ifa.is_null() {
+        unsafe {
+            let addr = (*ifa).ifa_addr;
+            let len = match addr.as_ref().map(|addr| addr.sa_family as libc::c_int) {
+                Some(libc::AF_INET) => mem::size_of::<libc::sockaddr_in>(),
+                Some(libc::AF_INET6) => mem::size_of::<libc::sockaddr_in6>(),
+                _ => 0,
+            };
+            if len > 0 {
+                let mut storage: libc::sockaddr_storage = mem::zeroed();
+                ptr::copy_nonoverlapping(addr as *const u8, &mut storage as *mut _ as *mut u8, len);
+                if let Ok(addr) = redir::to_socket_addr(&storage) {
+                    ips.push(acl::unmap(&addr.ip()));
+                }
+            }
+            ifa = (*ifa).ifa_next;
+        }
+    }
+    unsafe { libc::freeifaddrs(ifaddrs) };
+    Ok(ips)
+}
+
+/// UDP socket receiving datagrams diverted by an iptables `TPROXY` rule,
+/// along with their original destinations.
+pub struct UdpListener {
+    io: PollEvented<mio::net::UdpSocket>,
+}
+
+impl UdpListener {
+    pub fn bind(addr: &SocketAddr) -> io::Result<UdpListener> {
+        let socket = transparent_socket(addr, Type::dgram())?;
+        set_recv_orig_dst(&socket, addr.is_ipv6())?;
+        socket.bind(&SockAddr::from(*addr))?;
+        let socket = mio::net::UdpSocket::from_socket(socket.into_udp_socket())?;
+        Ok(UdpListener {
+            io: PollEvented::new(socket)?,
+        })
+    }
+
+    /// Receives a datagram, returning its length, source and original
+    /// destination.
+    pub async fn recv(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr, SocketAddr)> {
+        poll_fn(|cx| self.poll_recv(cx, buf)).await
+    }
+
+    fn poll_recv(
+        &self,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<(usize, SocketAddr, SocketAddr)>> {
+        match self.io.poll_read_ready(cx, mio::Ready::readable()) {
+            Poll::Pending => return Poll::Pending,
+            Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+            Poll::Ready(Ok(_)) => {}
+        }
+        match recv_msg(self.io.get_ref().as_raw_fd(), buf) {
+            Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                self.io.clear_read_ready(cx, mio::Ready::readable())?;
+                Poll::Pending
+            }
+            recv => Poll::Ready(recv),
+        }
+    }
+}
+
+fn recv_msg(fd: RawFd, buf: &mut [u8]) -> io::Result<(usize, SocketAddr, SocketAddr)> {
+    let mut src: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    // u64 keeps the control messages aligned
+    let mut control = [0_u64; 16];
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_name = &mut src as *mut _ as *mut libc::c_void;
+    msg.msg_namelen = mem::size_of_val(&src) as libc::socklen_t;
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = mem::size_of_val(&control) as _;
+
+    let n = unsafe { libc::recvmsg(fd, &mut msg, 0) };
+    if n < 0 {
+        return Err(Error::last_os_error());
+    }
+
+    let mut dst = None;
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if is_orig_dst(&*cmsg) {
+                let mut addr: libc::sockaddr_storage = mem::zeroed();
+                let data = libc::CMSG_DATA(cmsg);
+                let len = (*cmsg).cmsg_len as usize - (data as usize - cmsg as usize);
+                ptr::copy_nonoverlapping(
+                    data,
+                    &mut addr as *mut _ as *mut u8,
+                    len.min(mem::size_of_val(&addr)),
+                );
+                dst = Some(redir::to_socket_addr(&addr)?);
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+    match dst {
+        None => Err(Error::new(
+            ErrorKind::InvalidData,
+            "missing original destination",
+        )),
+        Some(dst) => Ok((n as usize, redir::to_socket_addr(&src)?, dst)),
+    }
+}